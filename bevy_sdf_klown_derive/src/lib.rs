@@ -1,110 +1,342 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{Data, DeriveInput, Fields, Ident, Type, parse_macro_input};
+use quote::{format_ident, quote};
+use syn::{Data, DeriveInput, Expr, Fields, Ident, LitStr, Type, parse_macro_input};
 
-// Convert a field into a vec of f32, expanding Vec2/Vec3/Vec4
-fn flatten_expr(ident: &Ident, ty: &Type) -> proc_macro2::TokenStream {
+// Options given with `#[gpu(..)]`:
+// on fields `skip`, `scale` and `scale = ..`, on variants `wgsl = "fnName"`, on the enum `import = "path"`.
+// A bare `scale` marks a length, it is multiplied by the `length_scale` given to `flatten_fields_scaled`
+#[derive(Default)]
+struct GpuAttr {
+    skip: bool,
+    length: bool,
+    scale: Option<Expr>,
+    wgsl: Option<String>,
+    import: Option<String>,
+}
+
+fn parse_gpu_attr(attrs: &[syn::Attribute]) -> syn::Result<GpuAttr> {
+    let mut attr = GpuAttr::default();
+    for a in attrs.iter().filter(|a| a.path().is_ident("gpu")) {
+        a.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                attr.skip = true;
+            } else if meta.path.is_ident("scale") {
+                if meta.input.peek(syn::Token![=]) {
+                    attr.scale = Some(meta.value()?.parse()?);
+                } else {
+                    attr.length = true;
+                }
+            } else if meta.path.is_ident("wgsl") {
+                attr.wgsl = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("import") {
+                attr.import = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else {
+                return Err(meta.error("expected `skip`, `scale`, `scale = ..`, `wgsl = ..` or `import = ..`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attr)
+}
+
+fn type_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(p) => Some(p.path.segments.last().unwrap().ident.to_string()),
+        _ => None,
+    }
+}
+
+// Number of f32 a field takes on the GPU, as a const expression
+fn count_expr(ty: &Type) -> proc_macro2::TokenStream {
+    if let Type::Array(array) = ty {
+        let len = &array.len;
+        let inner = count_expr(&array.elem);
+        return quote! { ((#len) * (#inner)) };
+    }
+    match type_name(ty).as_deref() {
+        Some("Vec2") => quote! { 2usize },
+        Some("Vec3") => quote! { 3usize },
+        Some("Vec4" | "Quat" | "Color" | "LinearRgba") => quote! { 4usize },
+        _ => quote! { 1usize },
+    }
+}
+
+// Integers are bitcast into the f32 data so they keep every bit, the shader bitcasts them back
+fn is_integer(ty: &Type) -> bool {
     match ty {
-        Type::Path(p) => {
-            let ident_str = p.path.segments.last().unwrap().ident.to_string();
-            match ident_str.as_str() {
-                "f32" => quote! { vec![#ident] },
-                "Vec2" => quote! { vec![#ident.x, #ident.y] },
-                "Vec3" => quote! { vec![#ident.x, #ident.y, #ident.z] },
-                "Vec4" => quote! { vec![#ident.x, #ident.y, #ident.z, #ident.w] },
-                _ => quote! { vec![#ident as f32] },
+        Type::Array(array) => is_integer(&array.elem),
+        _ => matches!(type_name(ty).as_deref(), Some("u32" | "i32" | "bool")),
+    }
+}
+
+// Statements pushing the f32 of `expr` into `v`, expanding vectors, colors and arrays
+fn flatten_expr(expr: proc_macro2::TokenStream, ty: &Type) -> syn::Result<proc_macro2::TokenStream> {
+    if let Type::Array(array) = ty {
+        let inner = flatten_expr(quote! { (*e) }, &array.elem)?;
+        return Ok(quote! {
+            for e in #expr.iter() {
+                #inner
             }
+        });
+    }
+    Ok(match type_name(ty).as_deref() {
+        Some("f32") => quote! { v.push(#expr); },
+        Some("u32") => quote! { v.push(f32::from_bits(#expr)); },
+        Some("i32") => quote! { v.push(f32::from_bits(#expr as u32)); },
+        Some("bool") => quote! { v.push(if #expr { 1.0 } else { 0.0 }); },
+        Some("Vec2" | "Vec3" | "Vec4" | "Quat") => quote! { v.extend(#expr.to_array()); },
+        Some("Color") => quote! { v.extend(#expr.to_linear().to_f32_array()); },
+        Some("LinearRgba") => quote! { v.extend(#expr.to_f32_array()); },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ty,
+                "unsupported #[gpu] field type, expected f32, u32, i32, bool, Vec2, Vec3, Vec4, \
+                 Quat, Color, LinearRgba or an array of them. Use #[gpu(skip)] to leave it out",
+            ));
         }
-        _ => quote! { vec![#ident as f32] },
+    })
+}
+
+// WGSL argument reading a field from `sd_field_data` at the offset `offset`
+fn wgsl_arg(offset: &str, ty: &Type) -> String {
+    let at = |i: usize| match i {
+        0 => format!("sd_field_data[data_index + {offset}]"),
+        _ => format!("sd_field_data[data_index + {offset} + {i}]"),
+    };
+    let vector = |n: usize| {
+        let components: Vec<String> = (0..n).map(at).collect();
+        format!("vec{n}f({})", components.join(", "))
+    };
+    match ty {
+        // Arrays are passed as the index of their first element
+        Type::Array(_) => format!("data_index + {offset}"),
+        _ => match type_name(ty).as_deref() {
+            Some("f32") => at(0),
+            Some("bool") => format!("{} != 0.0", at(0)),
+            Some("u32") => format!("bitcast<u32>({})", at(0)),
+            Some("i32") => format!("bitcast<i32>({})", at(0)),
+            Some("Vec2") => vector(2),
+            Some("Vec3") => vector(3),
+            Some("Vec4" | "Quat" | "Color" | "LinearRgba") => vector(4),
+            _ => at(0),
+        },
     }
 }
 
-#[proc_macro_derive(EnumVariantGpuFields)]
+fn screaming_snake_case(ident: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in ident.chars().enumerate() {
+        if c.is_uppercase() && i != 0 && !out.ends_with('_') {
+            out.push('_');
+        }
+        out.push(c.to_ascii_uppercase());
+    }
+    out
+}
+
+// NOTE: Every variant gets `const`s with the offset of its fields in the flattened data,
+// e.g. `SdShape::CAPSULE_RADIUS` and `SdShape::CAPSULE_FIELD_COUNT`, and its type id
+// `SdShape::CAPSULE_TYPE_ID`. `gpu_layout_wgsl()` emits the same values as WGSL consts,
+// e.g. `SD_SHAPE_CAPSULE_RADIUS`, and `gpu_select_arms_wgsl()` the `switch` arms calling
+// `sd<Variant>(p, fields..)` (or the `#[gpu(wgsl = "..")]` function) for each type id.
+#[proc_macro_derive(EnumVariantGpuFields, attributes(gpu))]
 pub fn enum_variant_gpu_fields(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
+    expand_gpu_fields(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_gpu_fields(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
     let name = input.ident;
+    let wgsl_prefix = screaming_snake_case(&name.to_string());
+    let wgsl_import = parse_gpu_attr(&input.attrs)?.import;
 
     let data_enum = match &input.data {
         Data::Enum(data) => data,
-        _ => panic!("EnumVariantGpuFields can only be derived for enums"),
+        Data::Struct(data) => {
+            return Err(syn::Error::new_spanned(
+                data.struct_token,
+                "EnumVariantGpuFields can only be derived for enums",
+            ));
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new_spanned(
+                data.union_token,
+                "EnumVariantGpuFields can only be derived for enums",
+            ));
+        }
     };
 
-    // Generate gpu_field_count() arms
-    let count_arms = data_enum.variants.iter().map(|v| {
+    let mut consts = Vec::new();
+    let mut wgsl_lines = Vec::new();
+    let mut count_arms = Vec::new();
+    let mut type_id_arms = Vec::new();
+    let mut flatten_arms = Vec::new();
+    let mut select_arms = String::new();
+    let mut wgsl_functions = Vec::new();
+    let mut type_id = quote! { 0u32 };
+
+    for v in data_enum.variants.iter() {
         let var_name = &v.ident;
-        let field_count_expr = match &v.fields {
-            Fields::Named(fields) => {
-                let counts = fields.named.iter().map(|f| {
-                    let bits = match &f.ty {
-                        Type::Path(p) => {
-                            match p.path.segments.last().unwrap().ident.to_string().as_str() {
-                                "f32" => 32,
-                                "Vec2" => 64,
-                                "Vec3" => 96,
-                                "Vec4" => 128,
-                                _ => 32,
-                            }
-                        }
-                        _ => 32,
-                    };
-                    quote! { ((#bits + 31)/32) as usize }
-                });
-                quote! { 0usize #( + #counts )* }
+        let var_upper = screaming_snake_case(&var_name.to_string());
+
+        // Type ids follow the discriminants, explicit or implicit
+        let type_id_name = format_ident!("{}_TYPE_ID", var_upper);
+        let wgsl_type_id_name = format!("{wgsl_prefix}_{type_id_name}");
+        if let Some((_, discriminant)) = &v.discriminant {
+            type_id = quote! { (#discriminant) as u32 };
+        }
+        consts.push(quote! { pub const #type_id_name: u32 = #type_id; });
+        wgsl_lines.push(quote! {
+            wgsl.push_str(&format!("const {}: u32 = {}u;\n", #wgsl_type_id_name, Self::#type_id_name));
+        });
+        type_id = quote! { Self::#type_id_name + 1 };
+
+        let wgsl_function = parse_gpu_attr(&v.attrs)?
+            .wgsl
+            .unwrap_or_else(|| format!("sd{var_name}"));
+        let mut wgsl_args = vec!["p".to_string()];
+
+        let fields: Vec<&syn::Field> = match &v.fields {
+            Fields::Named(fields) => fields.named.iter().collect(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(fields) => {
+                return Err(syn::Error::new_spanned(
+                    fields,
+                    "EnumVariantGpuFields variants must have named fields or no fields",
+                ));
             }
-            _ => panic!("All variants must be struct variants with named fields"),
         };
-        quote! { Self::#var_name { .. } => #field_count_expr }
-    });
 
-    // Generate flatten_fields() arms
-    let flatten_arms = data_enum.variants.iter().map(|v| {
-        let var_name = &v.ident;
-        let fields_named = match &v.fields {
-            Fields::Named(fields) => fields
-                .named
-                .iter()
-                .map(|f| f.ident.as_ref().unwrap())
-                .collect::<Vec<_>>(),
-            _ => panic!("All variants must be struct variants with named fields"),
-        };
+        let mut offset = quote! { 0usize };
+        let mut bound: Vec<&Ident> = Vec::new();
+        let mut pushes = Vec::new();
+
+        for f in fields {
+            let attr = parse_gpu_attr(&f.attrs)?;
+            if attr.skip {
+                continue;
+            }
+
+            let ident = f.ident.as_ref().unwrap();
+            let const_name = format_ident!(
+                "{}_{}",
+                var_upper,
+                ident.to_string().to_ascii_uppercase()
+            );
+            let wgsl_name = format!("{wgsl_prefix}_{const_name}");
+            wgsl_args.push(wgsl_arg(&wgsl_name, &f.ty));
+            consts.push(quote! { pub const #const_name: usize = #offset; });
+            wgsl_lines.push(quote! {
+                wgsl.push_str(&format!("const {}: u32 = {}u;\n", #wgsl_name, Self::#const_name));
+            });
 
-        // Generate expressions for each field
-        let exprs = fields_named.iter().map(|f| {
-            flatten_expr(
-                f,
-                &v.fields
-                    .iter()
-                    .find(|ff| ff.ident.as_ref() == Some(f))
-                    .unwrap()
-                    .ty,
-            )
+            let count = count_expr(&f.ty);
+            offset = quote! { Self::#const_name + #count };
+
+            let push = flatten_expr(quote! { (*#ident) }, &f.ty)?;
+            if (attr.length || attr.scale.is_some()) && is_integer(&f.ty) {
+                return Err(syn::Error::new_spanned(
+                    &f.ty,
+                    "#[gpu(scale)] only applies to float fields",
+                ));
+            }
+            let scale = match (attr.scale, attr.length) {
+                (Some(scale), true) => Some(quote! { (#scale) * length_scale }),
+                (Some(scale), false) => Some(quote! { (#scale) }),
+                (None, true) => Some(quote! { length_scale }),
+                (None, false) => None,
+            };
+            pushes.push(match scale {
+                Some(scale) => quote! {
+                    let start = v.len();
+                    #push
+                    for x in v[start..].iter_mut() {
+                        *x *= #scale;
+                    }
+                },
+                None => push,
+            });
+            bound.push(ident);
+        }
+
+        let count_name = format_ident!("{}_FIELD_COUNT", var_upper);
+        let wgsl_count_name = format!("{wgsl_prefix}_{count_name}");
+        consts.push(quote! { pub const #count_name: usize = #offset; });
+        wgsl_lines.push(quote! {
+            wgsl.push_str(&format!("const {}: u32 = {}u;\n", #wgsl_count_name, Self::#count_name));
         });
 
-        // Generate match arm with field bindings
-        quote! {
-            Self::#var_name { #(#fields_named),* } => {
-                let mut v = Vec::new();
-                #(v.extend(#exprs);)*
+        let pattern = match &v.fields {
+            Fields::Unit => quote! { Self::#var_name },
+            _ => quote! { Self::#var_name { #(#bound,)* .. } },
+        };
+        count_arms.push(quote! { #pattern => Self::#count_name });
+        type_id_arms.push(quote! { #pattern => Self::#type_id_name });
+        select_arms.push_str(&format!(
+            "        case {wgsl_type_id_name} {{\n            return {wgsl_function}({});\n        }}\n",
+            wgsl_args.join(", ")
+        ));
+        if !wgsl_functions.contains(&wgsl_function) {
+            wgsl_functions.push(wgsl_function);
+        }
+        flatten_arms.push(quote! {
+            #pattern => {
+                let mut v = Vec::with_capacity(Self::#count_name);
+                #(#pushes)*
                 v
             }
-        }
-    });
+        });
+    }
 
-    let expanded = quote! {
+    let wgsl_imports = match wgsl_import {
+        Some(path) => format!("#import {path}::{{\n    {},\n}}\n", wgsl_functions.join(",\n    ")),
+        None => String::new(),
+    };
+
+    Ok(quote! {
+        #[allow(unused_variables)]
         impl #name {
+            #(#consts)*
+
             pub fn gpu_field_count(&self) -> usize {
                 match self {
                     #(#count_arms),*
                 }
             }
 
+            pub fn gpu_type_id(&self) -> u32 {
+                match self {
+                    #(#type_id_arms),*
+                }
+            }
+
             pub fn flatten_fields(&self) -> Vec<f32> {
+                self.flatten_fields_scaled(1.0)
+            }
+
+            // Fields marked `#[gpu(scale)]` are multiplied by `length_scale`
+            pub fn flatten_fields_scaled(&self, length_scale: f32) -> Vec<f32> {
                 match self {
                     #(#flatten_arms),*
                 }
             }
-        }
-    };
 
-    TokenStream::from(expanded)
+            pub fn gpu_layout_wgsl() -> String {
+                let mut wgsl = String::new();
+                #(#wgsl_lines)*
+                wgsl
+            }
+
+            // `case` arms of a `switch` on the type id, `p` and `data_index` must be in scope
+            pub fn gpu_select_arms_wgsl() -> &'static str {
+                #select_arms
+            }
+
+            pub fn gpu_imports_wgsl() -> &'static str {
+                #wgsl_imports
+            }
+        }
+    })
 }
@@ -43,8 +43,8 @@ fn setup(mut commands: Commands) {
         op_patients![
             (
                 SdShape::RoundBox {
-                    bounds: Vec3::new(10.0, 5.0, 5.0),
-                    radius: 1.0,
+                    bounds: Vec3::new(5.0, 2.5, 2.5),
+                    radius: 0.5,
                 },
                 SdModStack {
                     modifiers: vec![
@@ -66,7 +66,7 @@ fn setup(mut commands: Commands) {
             ),
             (
                 SdShape::Box {
-                    bounds: Vec3::new(2.0, 4.0, 2.0)
+                    bounds: Vec3::new(1.0, 2.0, 1.0)
                 },
                 SdModStack {
                     modifiers: vec![
@@ -41,8 +41,8 @@ fn setup(mut commands: Commands) {
         op_patients![
             (
                 SdShape::BoxFrame {
-                    bounds: Vec3::new(5.0, 0.4, 1.5),
-                    edge: 0.2,
+                    bounds: Vec3::new(2.5, 0.2, 0.75),
+                    edge: 0.1,
                 },
                 SdModStack {
                     modifiers: vec![
@@ -68,7 +68,7 @@ fn setup(mut commands: Commands) {
                 },
             ),
             (
-                SdShape::Bunny { s: 6. },
+                SdShape::Bunny { s: 3. },
                 SdModStack {
                     modifiers: Vec::new()
                 },
@@ -35,7 +35,7 @@ fn setup(mut commands: Commands) {
                 op_patients![
                     (
                         SdShape::MengerSponge {
-                            scale: 5.0,
+                            scale: 2.5,
                             iter: 10.0,
                         },
                         SdMaterial {
@@ -48,7 +48,7 @@ fn setup(mut commands: Commands) {
                     (
                         AnimateMadelBulb,
                         SdShape::MandelBulb {
-                            scale: 5.0,
+                            scale: 2.5,
                             expo: 8.0,
                             iter: 10.0,
                             b_offset: 0.0,
@@ -64,7 +64,7 @@ fn setup(mut commands: Commands) {
             ),
             (
                 SdShape::JuliaQuaternion {
-                    scale: 5.0,
+                    scale: 2.5,
                     iter: 30.0,
                 },
                 Transform::from_xyz(10.0, 0.5, 0.0),
@@ -6,13 +6,12 @@ use bevy::prelude::*;
 use bevy::render::view::Hdr;
 // use bevy_egui::EguiPlugin;
 // use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use bevy_sdf_klown::engine::object::{SdMod, SdModStack};
+use bevy_sdf_klown::RayMarchingPlugin;
 use bevy_sdf_klown::engine::{
+    builder::SdNode,
     camera::RayMarchCamera,
-    object::{SdMaterial, SdShape},
-    op::SdBlend,
+    object::{SdMaterial, SdMod, SdShape},
 };
-use bevy_sdf_klown::{RayMarchingPlugin, op_patients};
 
 fn main() {
     App::new()
@@ -52,56 +51,39 @@ fn setup(
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    // WARN: A SdOp will only take in acount TOW RelationShips to it using SdOperatedBy
-    // any amount SdOperatedBy used above or under that can BREAK the raymarcher in unexpected ways
-
     // Raymarched Scene
-    commands.spawn((
-        SdBlend::SmoothUnion { k: 1.0 },
-        op_patients![
-            (
-                SdShape::BoxFrame {
-                    bounds: Vec3::splat(3.0),
-                    edge: 0.5
-                },
-                Transform::from_xyz(0.0, 1.0, 0.0).with_rotation(Quat::from_rotation_x(FRAC_PI_2)),
-                MeshMaterial3d(materials.add(Color::srgb(1., 1., 1.))),
+    SdNode::box_frame(Vec3::splat(1.5), 0.25)
+        .transform(
+            Transform::from_xyz(0.0, 1.0, 0.0).with_rotation(Quat::from_rotation_x(FRAC_PI_2)),
+        )
+        .standard_material(materials.add(Color::srgb(1., 1., 1.)))
+        .smooth_union(
+            SdNode::shape(SdShape::RoundedCylinder {
+                height: 1.0,
+                radius: 1.0,
+                edge_radius: 0.5,
+            })
+            .translate(Vec3::new(0.0, -2.0, 0.0))
+            .modifier(SdMod::InfArray {
+                c: Vec3::new(5.0, 10000.0, 5.0),
+            })
+            .modifier(SdMod::Elongate {
+                h: Vec3::new(3.0, 0.0, 3.0),
+            })
+            .material(SdMaterial {
+                color: LinearRgba::new(0.8, 0.0, 0.1, 1.0).into(),
+                roughness: 0.5,
+                ..default()
+            })
+            .chamfer_intersect(
+                SdNode::shape(SdShape::Gyroid { height: 2.5 })
+                    .translate(Vec3::new(2.0, 0.5, 0.0))
+                    .standard_material(materials.add(Color::srgb(0.5, 0.5, 1.))),
+                0.2,
             ),
-            (
-                SdBlend::ChamferIntersect { radius: 0.2 },
-                op_patients![
-                    (
-                        SdShape::RoundedCylinder {
-                            height: 2.0,
-                            radius: 2.0,
-                            edge_radius: 1.0
-                        },
-                        SdMaterial {
-                            color: LinearRgba::new(0.8, 0.0, 0.1, 1.0).into(),
-                            roughness: 0.5,
-                            ..default()
-                        },
-                        Transform::from_xyz(0.0, -2.0, 0.0),
-                        SdModStack {
-                            modifiers: vec![
-                                SdMod::InfArray {
-                                    c: Vec3::new(5.0, 10000.0, 5.0),
-                                },
-                                SdMod::Elongate {
-                                    h: Vec3::new(3.0, 0.0, 3.0)
-                                },
-                            ]
-                        },
-                    ),
-                    (
-                        SdShape::Gyroid { height: 2.5 },
-                        Transform::from_xyz(2.0, 0.5, 0.0),
-                        MeshMaterial3d(materials.add(Color::srgb(0.5, 0.5, 1.))),
-                    )
-                ]
-            )
-        ],
-    ));
+            1.0,
+        )
+        .spawn(&mut commands);
 
     // Polygonal Scene
     commands.spawn((
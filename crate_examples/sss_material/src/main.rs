@@ -33,7 +33,7 @@ fn setup(mut commands: Commands) {
         op_patients![
             (
                 SdShape::Box {
-                    bounds: Vec3::new(10.0, 5.0, 10.0),
+                    bounds: Vec3::new(5.0, 2.5, 5.0),
                 },
                 SdModStack {
                     modifiers: vec![SdMod::Twist { k: 0.1 }]
@@ -48,7 +48,7 @@ fn setup(mut commands: Commands) {
             ),
             (
                 SdShape::Box {
-                    bounds: Vec3::new(2.0, 4.0, 2.0)
+                    bounds: Vec3::new(1.0, 2.0, 1.0)
                 },
                 SdModStack {
                     modifiers: vec![
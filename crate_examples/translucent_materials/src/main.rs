@@ -0,0 +1,90 @@
+use bevy::camera_controller::free_camera::{FreeCamera, FreeCameraPlugin};
+use bevy::core_pipeline::prepass::{DepthPrepass, NormalPrepass};
+use bevy::prelude::*;
+use bevy::render::view::Hdr;
+use bevy_sdf_klown::RayMarchingPlugin;
+use bevy_sdf_klown::engine::{builder::SdNode, camera::RayMarchCamera, object::SdMaterial};
+
+fn main() {
+    App::new()
+        .add_plugins((
+            DefaultPlugins,
+            RayMarchingPlugin,
+            FreeCameraPlugin,
+        ))
+        .add_systems(Startup, setup)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // Raymarched Scene
+    // The alpha blended sphere goes through the translucent blit, the meshes behind it stay visible
+    SdNode::sphere(1.0)
+        .translate(Vec3::new(-1.5, 1.0, 0.0))
+        .material(SdMaterial {
+            color: LinearRgba::new(0.1, 0.6, 1.0, 0.35).into(),
+            roughness: 0.2,
+            alpha_mode: AlphaMode::Blend,
+            ..default()
+        })
+        // The glass box refracts the meshes behind it and stays opaque in the blit
+        .union(
+            SdNode::round_box(Vec3::splat(0.8), 0.1)
+                .translate(Vec3::new(1.5, 1.0, 0.0))
+                .material(SdMaterial {
+                    color: LinearRgba::new(1.0, 1.0, 1.0, 1.0).into(),
+                    roughness: 0.05,
+                    specular_transmission: 1.0,
+                    ior: 1.5,
+                    attenuation_color: LinearRgba::new(0.9, 0.5, 0.2, 1.0).into(),
+                    attenuation_distance: 2.0,
+                    ..default()
+                }),
+        )
+        .spawn(&mut commands);
+
+    // Polygonal Scene
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::default().mesh().size(20.0, 20.0))),
+        MeshMaterial3d(materials.add(Color::srgb(0.3, 0.5, 0.3))),
+    ));
+    for x in [-1.5, 1.5] {
+        commands.spawn((
+            Mesh3d(meshes.add(Cuboid::new(0.5, 3.0, 0.5))),
+            MeshMaterial3d(materials.add(Color::srgb(0.9, 0.2, 0.2))),
+            Transform::from_xyz(x, 1.5, -2.5),
+        ));
+    }
+
+    // Light
+    commands.spawn((
+        PointLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        Transform::from_xyz(4.0, 6.0, 4.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+
+    // Camera
+    commands.spawn((
+        RayMarchCamera {
+            transmission_steps: 2,
+            ..default()
+        },
+        Camera3d::default(),
+        Hdr,
+        Camera {
+            msaa_writeback: MsaaWriteback::Off,
+            ..default()
+        },
+        FreeCamera::default(),
+        Msaa::Off,
+        DepthPrepass::default(),
+        NormalPrepass::default(),
+        Transform::from_xyz(0.0, 2.5, 7.0).looking_at(Vec3::new(0.0, 1.0, 0.0), Vec3::Y),
+    ));
+}
@@ -0,0 +1,231 @@
+use bevy::prelude::*;
+
+use crate::engine::RAY_MARCH_BLENDS_HANDLE;
+
+// NOTE: Registered operators get the type ids from here, the SdBlend variants must stay below it
+pub const SD_CUSTOM_BLEND_TYPE_ID_START: u8 = 0x80;
+
+// Blend of two distances evaluated on the CPU, returns the distance and the material weight of `d1`
+pub type SdCustomBlendCpu = fn(f32, f32, Vec4) -> Vec2;
+
+// Type id of an operator added to the SdBlendRegistry
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SdCustomBlendId(pub u8);
+
+#[derive(Clone)]
+pub struct SdCustomBlendDescriptor {
+    // Name of the WGSL function, it must be unique
+    pub name: String,
+    // WGSL source defining `fn <name>(d1: f32, d2: f32, params: vec4f) -> vec2f`,
+    // returning the distance and the material weight of `d1` like the `op*` functions of utils.wgsl
+    pub wgsl: String,
+    pub cpu: Option<SdCustomBlendCpu>,
+}
+
+// Blend operators defined by the app, used with `SdBlend::Custom`.
+// Operators can only be added so the type ids given out stay valid.
+#[derive(Resource, Default, Clone)]
+pub struct SdBlendRegistry {
+    blends: Vec<SdCustomBlendDescriptor>,
+}
+
+impl SdBlendRegistry {
+    // The WGSL source can import anything from `bevy_sdf::utils`
+    pub fn register(&mut self, name: impl Into<String>, wgsl: impl Into<String>) -> SdCustomBlendId {
+        self.register_descriptor(SdCustomBlendDescriptor {
+            name: name.into(),
+            wgsl: wgsl.into(),
+            cpu: None,
+        })
+    }
+
+    pub fn register_with_cpu(
+        &mut self,
+        name: impl Into<String>,
+        wgsl: impl Into<String>,
+        cpu: SdCustomBlendCpu,
+    ) -> SdCustomBlendId {
+        self.register_descriptor(SdCustomBlendDescriptor {
+            name: name.into(),
+            wgsl: wgsl.into(),
+            cpu: Some(cpu),
+        })
+    }
+
+    pub fn register_descriptor(&mut self, descriptor: SdCustomBlendDescriptor) -> SdCustomBlendId {
+        let type_id = SD_CUSTOM_BLEND_TYPE_ID_START as usize + self.blends.len();
+        assert!(type_id <= u8::MAX as usize, "SdBlendRegistry is full");
+        assert!(
+            self.blends.iter().all(|b| b.name != descriptor.name),
+            "Custom blend {} is already registered",
+            descriptor.name
+        );
+
+        self.blends.push(descriptor);
+        SdCustomBlendId(type_id as u8)
+    }
+
+    pub fn get(&self, id: SdCustomBlendId) -> Option<&SdCustomBlendDescriptor> {
+        self.blends
+            .get(id.0.checked_sub(SD_CUSTOM_BLEND_TYPE_ID_START)? as usize)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SdCustomBlendId, &SdCustomBlendDescriptor)> {
+        self.blends
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (SdCustomBlendId(SD_CUSTOM_BLEND_TYPE_ID_START + i as u8), b))
+    }
+
+    // CPU blend of a registered operator, None if it has no CPU twin.
+    // `rev` swaps the distances the same way the shader does.
+    pub fn blend(
+        &self,
+        id: SdCustomBlendId,
+        rev: bool,
+        d1: f32,
+        d2: f32,
+        params: Vec4,
+    ) -> Option<Vec2> {
+        let cpu = self.get(id)?.cpu?;
+        Some(match rev {
+            false => cpu(d1, d2, params),
+            true => {
+                let blend = cpu(d2, d1, params);
+                Vec2::new(blend.x, 1. - blend.y)
+            }
+        })
+    }
+
+    // Source of the `bevy_sdf::blends` shader library, `select_blend` falls back on it for unknown type ids
+    pub(crate) fn gpu_blends_wgsl(&self) -> String {
+        let imports: String = self
+            .blends
+            .iter()
+            .map(|b| format!("#import bevy_sdf::custom_blends::{0}::{0}\n", b.name))
+            .collect();
+        let arms: String = self
+            .iter()
+            .map(|(id, b)| {
+                format!(
+                    "        case {}u {{
+            let params = custom_blend_params(op.data_index);
+            if op.rev {{
+                let blend = {1}(d2, d1, params);
+                return vec2f(blend.x, 1.0 - blend.y);
+            }}
+            return {1}(d1, d2, params);
+        }}
+",
+                    id.0, b.name
+                )
+            })
+            .collect();
+
+        format!(
+            "#define_import_path bevy_sdf::blends
+
+#import bevy_sdf::bindings::sd_field_data
+#import bevy_sdf::types::SdBlend
+#import bevy_sdf::utils::opUnion
+{imports}
+fn custom_blend_params(i: u32) -> vec4f {{
+    return vec4f(sd_field_data[i], sd_field_data[i + 1u], sd_field_data[i + 2u], sd_field_data[i + 3u]);
+}}
+
+fn select_custom_blend(op: SdBlend, d1: f32, d2: f32) -> vec2f {{
+    switch op.type_id {{
+{arms}        default {{
+            return opUnion(d1, d2);
+        }}
+    }}
+}}
+"
+        )
+    }
+}
+
+// Every registered operator is its own shader library imported by the generated `bevy_sdf::blends`
+pub(crate) fn update_custom_blend_shaders(
+    registry: Res<SdBlendRegistry>,
+    mut shaders: ResMut<Assets<Shader>>,
+    mut handles: Local<Vec<Handle<Shader>>>,
+) {
+    // The registry only grows, the libraries already added are kept as they are
+    for blend in registry.blends.iter().skip(handles.len()) {
+        handles.push(shaders.add(Shader::from_wgsl(
+            format!(
+                "#define_import_path bevy_sdf::custom_blends::{}\n\n{}",
+                blend.name, blend.wgsl
+            ),
+            format!("bevy_sdf/custom_blends/{}.wgsl", blend.name),
+        )));
+    }
+
+    shaders
+        .insert(
+            RAY_MARCH_BLENDS_HANDLE.id(),
+            Shader::from_wgsl(registry.gpu_blends_wgsl(), "bevy_sdf/blends.wgsl"),
+        )
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Like `opSmoothUnion`, returns the distance and the weight of `d1`
+    fn weighted_min(d1: f32, d2: f32, params: Vec4) -> Vec2 {
+        Vec2::new(d1.min(d2) - params.x, if d1 < d2 { 1. } else { 0. })
+    }
+
+    #[test]
+    fn ids_stop_at_u8_max() {
+        let mut registry = SdBlendRegistry::default();
+        let ids: Vec<_> = (SD_CUSTOM_BLEND_TYPE_ID_START..=u8::MAX)
+            .map(|i| registry.register(format!("blend_{i}"), ""))
+            .collect();
+        assert_eq!(ids[0], SdCustomBlendId(SD_CUSTOM_BLEND_TYPE_ID_START));
+        assert_eq!(ids[ids.len() - 1], SdCustomBlendId(u8::MAX));
+    }
+
+    #[test]
+    #[should_panic(expected = "SdBlendRegistry is full")]
+    fn registering_past_u8_max_panics() {
+        let mut registry = SdBlendRegistry::default();
+        for i in SD_CUSTOM_BLEND_TYPE_ID_START..=u8::MAX {
+            registry.register(format!("blend_{i}"), "");
+        }
+        registry.register("one_too_many", "");
+    }
+
+    #[test]
+    fn cpu_blend_swaps_distances_when_rev() {
+        let mut registry = SdBlendRegistry::default();
+        let id = registry.register_with_cpu("weighted_min", "", weighted_min);
+        let params = Vec4::new(0.5, 0., 0., 0.);
+
+        assert_eq!(registry.blend(id, false, 1., 2., params), Some(Vec2::new(0.5, 1.)));
+        // The weight stays the one of `d1` once the distances are swapped back
+        assert_eq!(registry.blend(id, true, 1., 2., params), Some(Vec2::new(0.5, 1.)));
+        assert_eq!(registry.blend(id, true, 2., 1., params), Some(Vec2::new(0.5, 0.)));
+    }
+
+    #[test]
+    fn cpu_blend_is_none_without_cpu_twin() {
+        let mut registry = SdBlendRegistry::default();
+        let id = registry.register("gpu_only", "");
+        assert_eq!(registry.blend(id, false, 1., 2., Vec4::ZERO), None);
+        assert_eq!(registry.blend(SdCustomBlendId(0), false, 1., 2., Vec4::ZERO), None);
+    }
+
+    #[test]
+    fn dispatch_swaps_distances_when_rev() {
+        let mut registry = SdBlendRegistry::default();
+        let id = registry.register("weighted_min", "");
+        let wgsl = registry.gpu_blends_wgsl();
+        assert!(wgsl.contains(&format!("case {}u {{", id.0)));
+        assert!(wgsl.contains("let blend = weighted_min(d2, d1, params);"));
+        assert!(wgsl.contains("return weighted_min(d1, d2, params);"));
+    }
+}
@@ -5,7 +5,7 @@ use bevy::{
     render::{
         render_graph::{NodeRunError, RenderGraphContext, ViewNode},
         render_resource::{
-            BindGroupEntries, BindGroupLayoutDescriptor, BindGroupLayoutEntries,
+            BindGroupEntries, BindGroupLayoutDescriptor, BindGroupLayoutEntries, BlendState,
             CachedRenderPipelineId, ColorTargetState, ColorWrites, CompareFunction, DepthBiasState,
             DepthStencilState, FragmentState, LoadOp, Operations, PipelineCache,
             RenderPassColorAttachment, RenderPassDescriptor, RenderPipelineDescriptor, Sampler,
@@ -43,8 +43,10 @@ impl ViewNode for BlitNode {
         let raymarch_blit_pipeline = world.resource::<RayMarchPipeline>();
         let pipeline_cache = world.resource::<PipelineCache>();
 
-        let Some(pipeline) = pipeline_cache.get_render_pipeline(raymarch_blit_pipeline.pipeline_id)
-        else {
+        let (Some(pipeline), Some(translucent_pipeline)) = (
+            pipeline_cache.get_render_pipeline(raymarch_blit_pipeline.pipeline_id),
+            pipeline_cache.get_render_pipeline(raymarch_blit_pipeline.translucent_pipeline_id),
+        ) else {
             return Ok(());
         };
 
@@ -74,8 +76,10 @@ impl ViewNode for BlitNode {
             occlusion_query_set: None,
         });
 
-        render_pass.set_render_pipeline(pipeline);
         render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.set_render_pipeline(pipeline);
+        render_pass.draw(0..3, 0..1);
+        render_pass.set_render_pipeline(translucent_pipeline);
         render_pass.draw(0..3, 0..1);
 
         Ok(())
@@ -87,6 +91,8 @@ struct RayMarchPipeline {
     layout: BindGroupLayoutDescriptor,
     sampler: Sampler,
     pipeline_id: CachedRenderPipelineId,
+    // Same blit for pixels with an alpha below 1, without depth writes
+    translucent_pipeline_id: CachedRenderPipelineId,
 }
 
 pub(crate) fn init_raymarch_blit_pipeline(
@@ -109,31 +115,42 @@ pub(crate) fn init_raymarch_blit_pipeline(
     let sampler = render_device.create_sampler(&SamplerDescriptor::default());
 
     let vertex_state = fullscreen_shader.to_vertex_state();
-    let pipeline_id = pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
-        label: Some("raymarch_blit_pipeline".into()),
-        layout: vec![layout.clone()],
-        vertex: vertex_state,
-        fragment: Some(FragmentState {
-            shader: RAY_MARCH_BLIT_PASS_HANDLE,
-            targets: vec![Some(ColorTargetState {
-                format: ViewTarget::TEXTURE_FORMAT_HDR,
-                blend: None,
-                write_mask: ColorWrites::ALL,
-            })],
+    let queue_pipeline = |translucent: bool| {
+        pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
+            label: Some("raymarch_blit_pipeline".into()),
+            layout: vec![layout.clone()],
+            vertex: vertex_state.clone(),
+            fragment: Some(FragmentState {
+                shader: RAY_MARCH_BLIT_PASS_HANDLE,
+                shader_defs: if translucent {
+                    vec!["SD_TRANSLUCENT".into()]
+                } else {
+                    vec![]
+                },
+                targets: vec![Some(ColorTargetState {
+                    format: ViewTarget::TEXTURE_FORMAT_HDR,
+                    // SDF surfaces are written premultiplied, opaque ones with an alpha of 1
+                    blend: Some(BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: ColorWrites::ALL,
+                })],
+                ..default()
+            }),
+            depth_stencil: Some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: !translucent,
+                depth_compare: CompareFunction::Greater,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
             ..default()
-        }),
-        depth_stencil: Some(DepthStencilState {
-            format: CORE_3D_DEPTH_FORMAT,
-            depth_write_enabled: true,
-            depth_compare: CompareFunction::Greater,
-            stencil: StencilState::default(),
-            bias: DepthBiasState::default(),
-        }),
-        ..default()
-    });
+        })
+    };
+    let pipeline_id = queue_pipeline(false);
+    let translucent_pipeline_id = queue_pipeline(true);
     commands.insert_resource(RayMarchPipeline {
         layout,
         sampler,
         pipeline_id,
+        translucent_pipeline_id,
     });
 }
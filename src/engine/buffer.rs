@@ -1,12 +1,28 @@
+use crate::engine::compile::SdCompiledMap;
 use bevy::{
     prelude::*,
     render::{extract_resource::ExtractResource, render_resource::Buffer},
 };
 
+// NOTE: Smallest size of `prefab_results` in ray_march.wgsl, also its size in compute_mask
+pub const SD_MIN_PREFAB_OPS: u32 = 8;
+
 #[derive(Resource, Clone, ExtractResource)]
 pub struct RayMarchBuffer {
     pub object: Buffer,
     pub operator: Buffer,
+    pub prefab_operator: Buffer,
     pub modifier: Buffer,
     pub field_data: Buffer,
+    // Deduplicated materials, objects reference them by index
+    pub material: Buffer,
+    // SdVolumes, a placeholder is bound when `volumes` is false
+    pub volume: Buffer,
+    pub volumes: bool,
+    // A material has specular transmission
+    pub transmission: bool,
+    // Length of the longest instance or volume program, rounded up to a power of two
+    pub max_prefab_ops: u32,
+    // Set with SdMapMode::Compiled
+    pub compiled_map: Option<SdCompiledMap>,
 }
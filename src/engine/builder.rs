@@ -0,0 +1,373 @@
+use bevy::{ecs::system::EntityCommands, prelude::*};
+
+use crate::engine::{
+    hierarchy::SdOperatedBy,
+    instance::{SdInstance, SdPrefab},
+    object::{SdMaterial, SdMod, SdModStack, SdShape},
+    op::SdBlend,
+    volume::SdVolume,
+};
+
+// NOTE: A typed alternative to nesting `op_patients!` tuples.
+// `SdNode::sphere(1.0).translate(..).smooth_union(SdNode::box_(..), 0.5)` builds a tree
+// that is spawned into the usual `SdBlend`/`SdShape`/`SdOperatedBy` components.
+#[derive(Debug, Clone)]
+pub enum SdNode {
+    Shape {
+        shape: SdShape,
+        transform: Transform,
+        modifiers: SdModStack,
+        material: Option<SdNodeMaterial>,
+    },
+    Instance(SdInstance),
+    Blend {
+        op: SdBlend,
+        lhs: Box<SdNode>,
+        rhs: Box<SdNode>,
+    },
+    // The node is spawned with an SdVolume and rendered as participating media
+    Volume {
+        volume: SdVolume,
+        node: Box<SdNode>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum SdNodeMaterial {
+    Sd(SdMaterial),
+    Standard(Handle<StandardMaterial>),
+}
+
+impl From<SdShape> for SdNode {
+    fn from(shape: SdShape) -> Self {
+        Self::shape(shape)
+    }
+}
+
+impl SdNode {
+    pub fn shape(shape: SdShape) -> Self {
+        Self::Shape {
+            shape,
+            transform: Transform::default(),
+            modifiers: SdModStack::default(),
+            material: None,
+        }
+    }
+
+    pub fn instance(prefab: Entity) -> Self {
+        Self::Instance(SdInstance {
+            prefab,
+            transform: Transform::default(),
+        })
+    }
+
+    pub fn sphere(radius: f32) -> Self {
+        Self::shape(SdShape::Sphere { radius })
+    }
+
+    pub fn ellipsoid(radius: Vec3) -> Self {
+        Self::shape(SdShape::Ellipsoid { radius })
+    }
+
+    pub fn box_(bounds: Vec3) -> Self {
+        Self::shape(SdShape::Box { bounds })
+    }
+
+    pub fn round_box(bounds: Vec3, radius: f32) -> Self {
+        Self::shape(SdShape::RoundBox { bounds, radius })
+    }
+
+    pub fn box_frame(bounds: Vec3, edge: f32) -> Self {
+        Self::shape(SdShape::BoxFrame { bounds, edge })
+    }
+
+    pub fn torus(major_radius: f32, minor_radius: f32) -> Self {
+        Self::shape(SdShape::Torus {
+            major_radius,
+            minor_radius,
+        })
+    }
+
+    pub fn capsule(a: Vec3, b: Vec3, radius: f32) -> Self {
+        Self::shape(SdShape::Capsule { a, b, radius })
+    }
+
+    pub fn cylinder(a: Vec3, b: Vec3, radius: f32) -> Self {
+        Self::shape(SdShape::Cylinder { a, b, radius })
+    }
+
+    pub fn plane(normal: Vec3, height: f32) -> Self {
+        Self::shape(SdShape::Plane { normal, height })
+    }
+
+    // Applies `transform` on top of every shape and instance of this node
+    pub fn transform(mut self, transform: Transform) -> Self {
+        self.for_each_leaf(&mut |node| match node {
+            Self::Shape { transform: t, .. } | Self::Instance(SdInstance { transform: t, .. }) => {
+                *t = transform * *t;
+            }
+            Self::Blend { .. } | Self::Volume { .. } => (),
+        });
+        self
+    }
+
+    pub fn translate(self, translation: Vec3) -> Self {
+        self.transform(Transform::from_translation(translation))
+    }
+
+    pub fn rotate(self, rotation: Quat) -> Self {
+        self.transform(Transform::from_rotation(rotation))
+    }
+
+    // Appends a modifier to the stack of a shape, it is applied in the shape's local space.
+    // WARN: The result of a blend can't be modified as a whole, see `modifier_each_leaf`
+    pub fn modifier(mut self, modifier: SdMod) -> Self {
+        self.push_modifier(modifier);
+        self
+    }
+
+    // Appends the modifier to the stack of every shape under this node, each in its own local space
+    pub fn modifier_each_leaf(mut self, modifier: SdMod) -> Self {
+        self.for_each_leaf(&mut |node| {
+            if let Self::Shape { modifiers, .. } = node {
+                modifiers.modifiers.push(modifier.clone());
+            }
+        });
+        self
+    }
+
+    // Sets the material of every shape under this node that doesn't have one yet
+    pub fn material(self, material: SdMaterial) -> Self {
+        self.fill_material(SdNodeMaterial::Sd(material))
+    }
+
+    pub fn standard_material(self, material: Handle<StandardMaterial>) -> Self {
+        self.fill_material(SdNodeMaterial::Standard(material))
+    }
+
+    pub fn blend(self, op: SdBlend, other: impl Into<SdNode>) -> Self {
+        Self::Blend {
+            op,
+            lhs: Box::new(self),
+            rhs: Box::new(other.into()),
+        }
+    }
+
+    pub fn union(self, other: impl Into<SdNode>) -> Self {
+        self.blend(SdBlend::Union, other)
+    }
+
+    pub fn subtract(self, other: impl Into<SdNode>) -> Self {
+        self.blend(SdBlend::Subtract { rev: false }, other)
+    }
+
+    pub fn intersect(self, other: impl Into<SdNode>) -> Self {
+        self.blend(SdBlend::Intersect, other)
+    }
+
+    pub fn chamfer_union(self, other: impl Into<SdNode>, radius: f32) -> Self {
+        self.blend(SdBlend::ChamferUnion { radius }, other)
+    }
+
+    pub fn chamfer_subtract(self, other: impl Into<SdNode>, radius: f32) -> Self {
+        self.blend(SdBlend::ChamferSubtract { rev: false, radius }, other)
+    }
+
+    pub fn chamfer_intersect(self, other: impl Into<SdNode>, radius: f32) -> Self {
+        self.blend(SdBlend::ChamferIntersect { radius }, other)
+    }
+
+    pub fn smooth_union(self, other: impl Into<SdNode>, k: f32) -> Self {
+        self.blend(SdBlend::SmoothUnion { k }, other)
+    }
+
+    pub fn smooth_subtract(self, other: impl Into<SdNode>, k: f32) -> Self {
+        self.blend(SdBlend::SmoothSubtract { rev: false, k }, other)
+    }
+
+    pub fn smooth_intersect(self, other: impl Into<SdNode>, k: f32) -> Self {
+        self.blend(SdBlend::SmoothIntersect { k }, other)
+    }
+
+    pub fn displace(self, other: impl Into<SdNode>, strength: f32) -> Self {
+        self.blend(
+            SdBlend::Displace {
+                rev: false,
+                strength,
+            },
+            other,
+        )
+    }
+
+    pub fn volume(self, volume: SdVolume) -> Self {
+        Self::Volume {
+            volume,
+            node: Box::new(self),
+        }
+    }
+
+    // WARN: The raymarcher only renders shapes operated by an SdBlend,
+    // a lone shape root is spawned but will not be visible unless it is a volume
+    pub fn spawn<'a>(self, commands: &'a mut Commands) -> EntityCommands<'a> {
+        if let Self::Shape { .. } | Self::Instance(_) = self {
+            warn!(
+                "SdNode {:?} is spawned as a lone root and will not be rendered, \
+                 blend it with another node or make it a volume",
+                self
+            );
+        }
+        let root = self.spawn_node(commands, None);
+        commands.entity(root)
+    }
+
+    // Spawns the tree as a prefab that is only rendered through SdInstance leaves
+    pub fn spawn_prefab<'a>(self, commands: &'a mut Commands) -> EntityCommands<'a> {
+        let mut root = self.spawn(commands);
+        root.insert(SdPrefab);
+        root
+    }
+
+    fn spawn_node(self, commands: &mut Commands, parent: Option<Entity>) -> Entity {
+        if let Self::Volume { volume, node } = self {
+            let entity = node.spawn_node(commands, parent);
+            commands.entity(entity).insert(volume);
+            return entity;
+        }
+
+        // SdOperatedBy goes in first so the SdIndex hook sees the full depth
+        let mut entity = commands.spawn_empty();
+        if let Some(parent) = parent {
+            entity.insert(SdOperatedBy(parent));
+        }
+
+        match self {
+            Self::Shape {
+                shape,
+                transform,
+                modifiers,
+                material,
+            } => {
+                entity.insert((shape, transform, modifiers));
+                match material {
+                    Some(SdNodeMaterial::Sd(material)) => entity.insert(material),
+                    Some(SdNodeMaterial::Standard(handle)) => {
+                        entity.insert(MeshMaterial3d(handle))
+                    }
+                    None => entity.insert(SdMaterial::default()),
+                };
+                entity.id()
+            }
+            Self::Instance(instance) => entity.insert(instance).id(),
+            Self::Blend { op, lhs, rhs } => {
+                let entity = entity.insert(op).id();
+                // The patient order matches `op_patients![lhs, rhs]`
+                lhs.spawn_node(commands, Some(entity));
+                rhs.spawn_node(commands, Some(entity));
+                entity
+            }
+            Self::Volume { .. } => unreachable!(),
+        }
+    }
+
+    fn push_modifier(&mut self, modifier: SdMod) {
+        match self {
+            Self::Shape { modifiers, .. } => modifiers.modifiers.push(modifier),
+            Self::Volume { node, .. } => node.push_modifier(modifier),
+            Self::Blend { .. } | Self::Instance(_) => warn!(
+                "SdNode::modifier only applies to shapes, {:?} is ignored. \
+                 Use modifier_each_leaf to modify every shape of a blend",
+                modifier
+            ),
+        }
+    }
+
+    fn fill_material(mut self, material: SdNodeMaterial) -> Self {
+        self.for_each_leaf(&mut |node| {
+            if let Self::Shape { material: m, .. } = node {
+                m.get_or_insert_with(|| material.clone());
+            }
+        });
+        self
+    }
+
+    fn for_each_leaf(&mut self, f: &mut impl FnMut(&mut SdNode)) {
+        match self {
+            Self::Shape { .. } | Self::Instance(_) => f(self),
+            Self::Blend { lhs, rhs, .. } => {
+                lhs.for_each_leaf(f);
+                rhs.for_each_leaf(f);
+            }
+            Self::Volume { node, .. } => node.for_each_leaf(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::hierarchy::SdOperatingOn;
+
+    fn spawn(world: &mut World, node: SdNode) -> Entity {
+        let mut commands = world.commands();
+        let root = node.spawn(&mut commands).id();
+        world.flush();
+        root
+    }
+
+    fn patients(world: &World, op: Entity) -> (Entity, Entity) {
+        world
+            .get::<SdOperatingOn>(op)
+            .unwrap()
+            .clone()
+            .get_sd_argunments()
+    }
+
+    #[test]
+    fn patients_keep_lhs_rhs_order() {
+        let mut world = World::new();
+        let root = spawn(
+            &mut world,
+            SdNode::sphere(1.)
+                .subtract(SdNode::box_(Vec3::ONE).smooth_union(SdNode::sphere(2.), 0.5)),
+        );
+
+        let (lhs, rhs) = patients(&world, root);
+        assert!(matches!(
+            world.get::<SdShape>(lhs),
+            Some(SdShape::Sphere { radius: 1. })
+        ));
+        assert!(matches!(
+            world.get::<SdBlend>(rhs),
+            Some(SdBlend::SmoothUnion { k: 0.5 })
+        ));
+
+        let (lhs, rhs) = patients(&world, rhs);
+        assert!(matches!(world.get::<SdShape>(lhs), Some(SdShape::Box { .. })));
+        assert!(matches!(
+            world.get::<SdShape>(rhs),
+            Some(SdShape::Sphere { radius: 2. })
+        ));
+    }
+
+    #[test]
+    fn transform_and_material_apply_to_every_leaf() {
+        let mut world = World::new();
+        let root = spawn(
+            &mut world,
+            SdNode::sphere(1.)
+                .translate(Vec3::X)
+                .union(SdNode::sphere(1.))
+                .translate(Vec3::Y)
+                .material(SdMaterial::default()),
+        );
+
+        let (lhs, rhs) = patients(&world, root);
+        assert_eq!(
+            world.get::<Transform>(lhs).unwrap().translation,
+            Vec3::new(1., 1., 0.)
+        );
+        assert_eq!(world.get::<Transform>(rhs).unwrap().translation, Vec3::Y);
+        assert!(world.get::<SdMaterial>(lhs).is_some());
+        assert!(world.get::<SdMaterial>(rhs).is_some());
+    }
+}
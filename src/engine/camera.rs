@@ -1,9 +1,40 @@
 use bevy::{
+    ecs::query::QueryItem,
     prelude::*,
-    render::{extract_component::ExtractComponent, render_resource::ShaderType},
+    render::{
+        extract_component::ExtractComponent, render_asset::RenderAssets,
+        render_resource::ShaderType, texture::GpuImage,
+    },
+    shader::ShaderDefVal,
 };
 
-#[derive(Component, Clone, Reflect, ExtractComponent, ShaderType)]
+// How SDF surfaces are shadowed, only lights with `shadows_enabled` cast SDF shadows.
+// Every mode is its own pipeline so the shadow code of the other modes is not compiled in.
+#[derive(Reflect, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[reflect(Default)]
+pub enum SdShadowMode {
+    Off,
+    // Any hit along the shadow ray fully occludes the light
+    Hard,
+    // Penumbra from the closest distance along the ray, scaled by shadow_softness
+    #[default]
+    Soft,
+    // Soft shadows with less banding where the ray grazes a surface, a bit more expensive
+    ImprovedSoft,
+}
+
+impl SdShadowMode {
+    pub(crate) fn shader_def(self) -> Option<ShaderDefVal> {
+        match self {
+            Self::Off => None,
+            Self::Hard => Some("SD_SHADOW_HARD".into()),
+            Self::Soft => Some("SD_SHADOW_SOFT".into()),
+            Self::ImprovedSoft => Some("SD_SHADOW_IMPROVED_SOFT".into()),
+        }
+    }
+}
+
+#[derive(Component, Clone, Reflect)]
 #[reflect(Component, Default)]
 pub struct RayMarchCamera {
     pub depth_scale: f32,
@@ -11,11 +42,28 @@ pub struct RayMarchCamera {
     pub w: f32,
     pub max_distance: f32,
     pub max_steps: u32,
+    pub shadow_mode: SdShadowMode,
+    // Raymarch the SDFs into the shadow maps of Bevy's lights so they shadow meshes.
+    // WARN: Off by default, it is a full screen raymarch per shadow view (6 per point light, one per cascade)
+    pub cast_shadow_maps: bool,
     pub shadow_eps: f32,
     pub shadow_max_steps: u32,
     pub shadow_max_distance: f32,
     pub shadow_softness: f32,
     pub normal_eps: f32,
+    // Surfaces up to reflection_max_roughness trace this many mirror rays through the SDFs,
+    // 0 keeps reflecting the environment map only
+    pub reflection_bounces: u32,
+    pub reflection_max_roughness: f32,
+    // Transparent surfaces a refracted ray goes through, like Camera3d::screen_space_specular_transmission_steps,
+    // 0 renders every material opaque. Refraction is only compiled in once a material has
+    // specular_transmission
+    pub transmission_steps: u32,
+    // SdVolumes are marched with steps of volume_step_size inside them, 0 steps skips them
+    pub volume_max_steps: u32,
+    pub volume_step_size: f32,
+    // Steps towards each light for the shadow the media casts on itself
+    pub volume_light_steps: u32,
 }
 
 impl Default for RayMarchCamera {
@@ -26,11 +74,103 @@ impl Default for RayMarchCamera {
             w: 0.5,
             max_steps: 500,
             max_distance: 500.,
+            shadow_mode: SdShadowMode::default(),
+            cast_shadow_maps: false,
             shadow_eps: 0.01,
             shadow_max_steps: 500,
             shadow_max_distance: 100.,
             shadow_softness: 0.02,
             normal_eps: 0.001,
+            reflection_bounces: 0,
+            reflection_max_roughness: 0.4,
+            transmission_steps: 1,
+            volume_max_steps: 128,
+            volume_step_size: 0.1,
+            volume_light_steps: 6,
         }
     }
 }
+
+// The `settings` of the shader, the shadow mode goes through the shader defs instead
+#[derive(Component, Clone, ShaderType)]
+pub struct RayMarchCameraUniform {
+    pub depth_scale: f32,
+    pub eps: f32,
+    pub w: f32,
+    pub max_distance: f32,
+    pub max_steps: u32,
+    pub shadow_eps: f32,
+    pub shadow_max_steps: u32,
+    pub shadow_max_distance: f32,
+    pub shadow_softness: f32,
+    pub normal_eps: f32,
+    // Same as the EnvironmentMapUniform of bevy_pbr, rotates the sample directions
+    pub environment_map_transform: Mat4,
+    pub environment_map_intensity: f32,
+    pub reflection_bounces: u32,
+    pub reflection_max_roughness: f32,
+    pub transmission_steps: u32,
+    pub volume_max_steps: u32,
+    pub volume_step_size: f32,
+    pub volume_light_steps: u32,
+}
+
+// Cubemaps of the EnvironmentMapLight of the view, the SDFs are lit by them like meshes
+#[derive(Component, Clone, Default)]
+pub struct RayMarchEnvironmentMap {
+    pub diffuse_map: Option<AssetId<Image>>,
+    pub specular_map: Option<AssetId<Image>>,
+}
+
+impl RayMarchEnvironmentMap {
+    // Both cubemaps, once they are uploaded
+    pub fn gpu_images<'a>(
+        &self,
+        images: &'a RenderAssets<GpuImage>,
+    ) -> Option<(&'a GpuImage, &'a GpuImage)> {
+        Some((images.get(self.diffuse_map?)?, images.get(self.specular_map?)?))
+    }
+}
+
+impl RayMarchCamera {
+    pub fn uniform(&self, environment_map: Option<&EnvironmentMapLight>) -> RayMarchCameraUniform {
+        RayMarchCameraUniform {
+            depth_scale: self.depth_scale,
+            eps: self.eps,
+            w: self.w,
+            max_distance: self.max_distance,
+            max_steps: self.max_steps,
+            shadow_eps: self.shadow_eps,
+            shadow_max_steps: self.shadow_max_steps,
+            shadow_max_distance: self.shadow_max_distance,
+            shadow_softness: self.shadow_softness,
+            normal_eps: self.normal_eps,
+            environment_map_transform: environment_map.map_or(Mat4::IDENTITY, |map| {
+                Transform::from_rotation(map.rotation).to_matrix().inverse()
+            }),
+            environment_map_intensity: environment_map.map_or(0.0, |map| map.intensity),
+            reflection_bounces: self.reflection_bounces,
+            reflection_max_roughness: self.reflection_max_roughness,
+            transmission_steps: self.transmission_steps,
+            volume_max_steps: self.volume_max_steps,
+            volume_step_size: self.volume_step_size,
+            volume_light_steps: self.volume_light_steps,
+        }
+    }
+}
+
+impl ExtractComponent for RayMarchCamera {
+    type QueryData = (&'static Self, Option<&'static EnvironmentMapLight>);
+    type QueryFilter = ();
+    type Out = (Self, RayMarchCameraUniform, RayMarchEnvironmentMap);
+
+    fn extract_component(
+        (camera, environment_map): QueryItem<'_, '_, Self::QueryData>,
+    ) -> Option<Self::Out> {
+        let maps = RayMarchEnvironmentMap {
+            diffuse_map: environment_map.map(|map| map.diffuse_map.id()),
+            specular_map: environment_map.map(|map| map.specular_map.id()),
+        };
+        Some((camera.clone(), camera.uniform(environment_map), maps))
+    }
+}
@@ -0,0 +1,223 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use bevy::{platform::collections::HashMap, prelude::*};
+
+// How the shader evaluates the SDF trees
+#[derive(Resource, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Resource, Default)]
+pub enum SdMapMode {
+    // `map()` walks the op buffer every sample
+    #[default]
+    Interpreted,
+    // A `map()` is generated for the current topology with every op and type id inlined,
+    // parameters still come from the buffers so only topology changes need a new shader.
+    // The interpreter is used while the specialized pipeline compiles.
+    Compiled,
+}
+
+// A leaf of the flattened tree as the compiled map sees it
+pub(crate) enum SdCompiledLeaf {
+    // Type id of the shape and the type ids of its modifiers with their index in sd_mod
+    Shape {
+        type_id: u32,
+        mods: Vec<(u32, usize)>,
+    },
+    // Instances and invalid leaves go through the interpreter functions
+    Object,
+}
+
+#[derive(Clone, Debug)]
+pub struct SdCompiledMap {
+    pub hash: u64,
+    pub shader: Handle<Shader>,
+}
+
+// NOTE: A compiled shader is the entry points of the raymarcher with `bevy_sdf::ray_march::map`
+// overridden, everything else comes from the `bevy_sdf::ray_march` library
+const COMPILED_MAP_IMPORTS: &str = "\
+#import bevy_sdf::bindings::{sd_object, sd_ops, sd_mod}
+#import bevy_sdf::selectors::{apply_transform, apply_mod}
+#import bevy_sdf::shapes::select_shape_dist
+#import bevy_sdf::types::{DistanceInfo, SdBlend, SdMod, unpack_sd_object, unpack_sd_blend, single_material}
+#import bevy_sdf::ray_march::{EMPTY_DIST, object_to_dist, blend_distance_info}
+";
+
+// NOTE: Past topologies are kept so going back and forth between two trees does not recompile,
+// the cache is cleared once it holds MAX_COMPILED_MAPS of them
+pub(crate) const MAX_COMPILED_MAPS: usize = 16;
+
+#[derive(Resource, Default)]
+pub(crate) struct SdCompiledMaps {
+    shaders: HashMap<u64, Handle<Shader>>,
+}
+
+impl SdCompiledMaps {
+    pub(crate) fn get_or_compile(
+        &mut self,
+        map_wgsl: String,
+        shaders: &mut Assets<Shader>,
+    ) -> SdCompiledMap {
+        let mut hasher = DefaultHasher::new();
+        map_wgsl.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if !self.shaders.contains_key(&hash) && self.shaders.len() >= MAX_COMPILED_MAPS {
+            self.shaders.clear();
+        }
+
+        let shader = self
+            .shaders
+            .entry(hash)
+            .or_insert_with(|| {
+                shaders.add(Shader::from_wgsl(
+                    format!(
+                        "{}{}\n{}",
+                        include_str!("../shaders/ray_march_pass.wgsl"),
+                        COMPILED_MAP_IMPORTS,
+                        map_wgsl
+                    ),
+                    format!("bevy_sdf/compiled/{hash:016x}.wgsl"),
+                ))
+            })
+            .clone();
+
+        SdCompiledMap { hash, shader }
+    }
+}
+
+// The `map()` override for a flattened tree, `ops` are the type id and patients of every op of sd_ops
+pub(crate) fn compiled_map_wgsl(leaves: &[SdCompiledLeaf], ops: &[(u32, u32, u32)]) -> String {
+    let nb_leaves = leaves.len() as u32;
+    let mut wgsl = String::from("override fn bevy_sdf::ray_march::map(p: vec3f) -> DistanceInfo {\n");
+
+    let patient = |wgsl: &mut String, index: u32| -> String {
+        if index >= nb_leaves {
+            return format!("r{}", index - nb_leaves);
+        }
+
+        match &leaves[index as usize] {
+            SdCompiledLeaf::Shape { type_id, mods } => {
+                wgsl.push_str(&format!(
+                    "    let o{index} = unpack_sd_object(sd_object[{index}u]);\n"
+                ));
+                let p_decl = if mods.is_empty() { "let" } else { "var" };
+                wgsl.push_str(&format!(
+                    "    {p_decl} p{index} = apply_transform(p, o{index}.transform);\n"
+                ));
+                for (mod_type_id, mod_index) in mods {
+                    wgsl.push_str(&format!(
+                        "    p{index} = apply_mod(p{index}, SdMod({mod_type_id}u, sd_mod[{mod_index}u].data_index, sd_mod[{mod_index}u].data));\n"
+                    ));
+                }
+                wgsl.push_str(&format!(
+                    "    let d{index} = DistanceInfo(select_shape_dist(p{index}, {type_id}u, o{index}.shape.data_index), single_material(o{index}.material));\n"
+                ));
+            }
+            SdCompiledLeaf::Object => {
+                wgsl.push_str(&format!("    let d{index} = object_to_dist({index}u, p);\n"));
+            }
+        }
+        format!("d{index}")
+    };
+
+    for (e, &(type_id, lhs, rhs)) in ops.iter().enumerate() {
+        let lhs = patient(&mut wgsl, lhs);
+        let rhs = patient(&mut wgsl, rhs);
+        wgsl.push_str(&format!(
+            "    let b{e} = unpack_sd_blend(sd_ops[{e}u].op);\n    let r{e} = blend_distance_info({lhs}, {rhs}, SdBlend({type_id}u, b{e}.rev, b{e}.data, b{e}.data_index));\n"
+        ));
+    }
+
+    match ops.len() {
+        0 => wgsl.push_str("    return DistanceInfo(EMPTY_DIST, single_material(0u));\n}\n"),
+        len => wgsl.push_str(&format!("    return r{};\n}}\n", len - 1)),
+    }
+    wgsl
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(type_id: u32) -> SdCompiledLeaf {
+        SdCompiledLeaf::Shape {
+            type_id,
+            mods: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ops_inline_their_type_id_and_patients() {
+        let leaves = [shape(0), shape(2), SdCompiledLeaf::Object];
+        // (leaf 0 smooth union leaf 1) subtract leaf 2
+        let wgsl = compiled_map_wgsl(&leaves, &[(6, 0, 1), (1, 3, 2)]);
+
+        assert!(
+            wgsl.starts_with("override fn bevy_sdf::ray_march::map(p: vec3f) -> DistanceInfo {\n")
+        );
+        assert!(wgsl.contains("select_shape_dist(p0, 0u, o0.shape.data_index)"));
+        assert!(wgsl.contains("select_shape_dist(p1, 2u, o1.shape.data_index)"));
+        assert!(wgsl.contains("let d2 = object_to_dist(2u, p);"));
+        assert!(
+            wgsl.contains("blend_distance_info(d0, d1, SdBlend(6u, b0.rev, b0.data, b0.data_index))")
+        );
+        assert!(
+            wgsl.contains("blend_distance_info(r0, d2, SdBlend(1u, b1.rev, b1.data, b1.data_index))")
+        );
+        assert!(wgsl.ends_with("    return r1;\n}\n"));
+    }
+
+    #[test]
+    fn mods_are_applied_in_order() {
+        let leaves = [
+            SdCompiledLeaf::Shape {
+                type_id: 0,
+                mods: vec![(8, 0), (0, 1)],
+            },
+            shape(1),
+        ];
+        let wgsl = compiled_map_wgsl(&leaves, &[(0, 0, 1)]);
+
+        assert!(wgsl.contains("var p0 = apply_transform(p, o0.transform);"));
+        assert!(wgsl.contains("let p1 = apply_transform(p, o1.transform);"));
+        let twist = wgsl.find("SdMod(8u, sd_mod[0u].data_index").unwrap();
+        let translate = wgsl.find("SdMod(0u, sd_mod[1u].data_index").unwrap();
+        assert!(twist < translate);
+    }
+
+    #[test]
+    fn no_ops_is_empty_space() {
+        let wgsl = compiled_map_wgsl(&[shape(0)], &[]);
+        assert!(wgsl.contains("return DistanceInfo(EMPTY_DIST, single_material(0u));"));
+    }
+
+    // Parameters are read from the buffers, only the topology is in the source
+    #[test]
+    fn same_topology_reuses_the_shader() {
+        let mut shaders = Assets::<Shader>::default();
+        let mut maps = SdCompiledMaps::default();
+        let topology = || compiled_map_wgsl(&[shape(0), shape(1)], &[(0, 0, 1)]);
+
+        let first = maps.get_or_compile(topology(), &mut shaders);
+        let second = maps.get_or_compile(topology(), &mut shaders);
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(first.shader, second.shader);
+        assert_eq!(shaders.len(), 1);
+
+        let other = compiled_map_wgsl(&[shape(0), shape(1)], &[(2, 0, 1)]);
+        assert_ne!(maps.get_or_compile(other, &mut shaders).hash, first.hash);
+    }
+
+    #[test]
+    fn cache_is_cleared_once_full() {
+        let mut shaders = Assets::<Shader>::default();
+        let mut maps = SdCompiledMaps::default();
+        for type_id in 0..MAX_COMPILED_MAPS as u32 {
+            maps.get_or_compile(compiled_map_wgsl(&[shape(type_id)], &[(0, 0, 0)]), &mut shaders);
+        }
+        assert_eq!(maps.shaders.len(), MAX_COMPILED_MAPS);
+
+        maps.get_or_compile(compiled_map_wgsl(&[shape(99)], &[(0, 0, 0)]), &mut shaders);
+        assert_eq!(maps.shaders.len(), 1);
+    }
+}
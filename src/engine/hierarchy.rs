@@ -1,9 +1,9 @@
 use bevy::{prelude::*, render::extract_component::ExtractComponent};
 
 #[cfg(feature = "skein")]
-use crate::engine::op::SdBlend;
+use crate::engine::{op::SdBlend, units::SdUnits};
 #[cfg(feature = "skein")]
-use bevy::ecs::{lifecycle::HookContext, world::DeferredWorld};
+use bevy::platform::collections::HashMap;
 
 #[derive(Component, Clone, ExtractComponent)]
 #[relationship(relationship_target = SdOperatingOn)]
@@ -37,42 +37,158 @@ macro_rules! op_patients {
 }
 
 // NOTE: A helper for skein that will automatically setup the SDF relationships
-// This must be added to every SdOp and SdShape in the skein scene
+// This must be added to every SdOp and SdShape in the skein scene.
+// Each node is operated by its nearest SdBlend ancestor, empties in between are skipped
 #[derive(Component, Reflect)]
 #[reflect(Component)]
-#[component(on_add = detect_op)]
 #[cfg(feature = "skein")]
 pub struct InitSkeinSdRelationShip;
 
+// Explicit patient order for skein nodes, patients without a slot fill the free ones
+// Lhs is the first patient, the one subtracted from by `Subtract { rev: false }`
+#[derive(Component, Reflect, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Component)]
+#[cfg(feature = "skein")]
+pub enum SdPatientSlot {
+    Lhs,
+    Rhs,
+}
+
+// Resolved in a system rather than an `on_add` hook so the whole scene
+// is spawned and the hook order of the nodes doesn't matter
 #[cfg(feature = "skein")]
-fn detect_op(mut world: DeferredWorld, HookContext { entity, .. }: HookContext) {
-    {
-        let mut commands = world.commands();
+#[allow(clippy::type_complexity)]
+pub(crate) fn init_skein_sd_relationships(
+    mut commands: Commands,
+    pending_query: Query<
+        (Entity, Option<&SdPatientSlot>, Has<SdBlend>),
+        With<InitSkeinSdRelationShip>,
+    >,
+    parent_query: Query<&ChildOf>,
+    sd_op_query: Query<(), With<SdBlend>>,
+) {
+    let mut patients = HashMap::<Entity, Vec<(Entity, Option<SdPatientSlot>)>>::default();
+
+    for (entity, slot, is_op) in pending_query.iter().sort::<Entity>() {
         commands.entity(entity).remove::<InitSkeinSdRelationShip>();
-    }
 
-    let parent = match world.get::<ChildOf>(entity) {
-        Some(p) => p.parent(),
-        None => return,
-    };
-    match world.get::<SdBlend>(parent) {
-        Some(_sd_op) => {
-            let mut command = world.commands();
-            command.entity(entity).insert(SdOperatedBy(parent));
-            return;
+        let op = parent_query
+            .iter_ancestors(entity)
+            .find(|&ancestor| sd_op_query.contains(ancestor));
+
+        match op {
+            Some(op) => patients.entry(op).or_default().push((entity, slot.copied())),
+            // A blend without an SdBlend ancestor is the root of a tree,
+            // unless the scene says otherwise its sizes follow Blender's
+            None if is_op => {
+                commands.entity(entity).insert_if_new(SdUnits::Blender);
+            }
+            None => warn!(
+                "Skein SDF node {:?} has no SdBlend ancestor and will not be rendered",
+                entity
+            ),
         }
-        None => (),
     }
 
-    let grand_parent = match world.get::<ChildOf>(parent) {
-        Some(p) => p.parent(),
-        None => return,
-    };
-    match world.get::<SdBlend>(grand_parent) {
-        Some(_sd_op) => {
-            let mut command = world.commands();
-            command.entity(entity).insert(SdOperatedBy(grand_parent));
+    for (op, op_patients) in patients {
+        if op_patients.len() != 2 {
+            warn!(
+                "SdBlend {:?} has {} patients in the skein scene, expected 2",
+                op,
+                op_patients.len()
+            );
+        }
+
+        let mut slots = [None; 2];
+        let mut unslotted = Vec::new();
+        for (entity, slot) in op_patients {
+            let i = match slot {
+                Some(SdPatientSlot::Lhs) => 0,
+                Some(SdPatientSlot::Rhs) => 1,
+                None => {
+                    unslotted.push(entity);
+                    continue;
+                }
+            };
+            match slots[i] {
+                None => slots[i] = Some(entity),
+                Some(other) => {
+                    warn!(
+                        "SdBlend {:?} has both {:?} and {:?} in slot {:?}",
+                        op, other, entity, slot
+                    );
+                    unslotted.push(entity);
+                }
+            }
+        }
+
+        let mut unslotted = unslotted.into_iter();
+        for slot in slots.iter_mut().filter(|slot| slot.is_none()) {
+            *slot = unslotted.next();
+        }
+
+        // Insertion order is the patient order of SdOperatingOn
+        for patient in slots.into_iter().flatten().chain(unslotted) {
+            commands.entity(patient).insert(SdOperatedBy(op));
         }
-        None => (),
+    }
+}
+
+#[cfg(all(test, feature = "skein"))]
+mod tests {
+    use super::*;
+    use bevy::ecs::system::RunSystemOnce;
+
+    // A skein scene with the patients of `op` spawned as its children in order
+    fn resolve(slots: [Option<SdPatientSlot>; 2]) -> (World, Entity, [Entity; 2]) {
+        let mut world = World::new();
+        let op = world
+            .spawn((SdBlend::Subtract { rev: false }, InitSkeinSdRelationShip))
+            .id();
+        let patients = slots.map(|slot| {
+            let mut patient = world.spawn((InitSkeinSdRelationShip, ChildOf(op)));
+            if let Some(slot) = slot {
+                patient.insert(slot);
+            }
+            patient.id()
+        });
+        world.run_system_once(init_skein_sd_relationships).unwrap();
+        (world, op, patients)
+    }
+
+    fn arguments(world: &World, op: Entity) -> (Entity, Entity) {
+        world
+            .get::<SdOperatingOn>(op)
+            .unwrap()
+            .clone()
+            .get_sd_argunments()
+    }
+
+    #[test]
+    fn slots_override_spawn_order() {
+        let (world, op, [a, b]) = resolve([Some(SdPatientSlot::Rhs), Some(SdPatientSlot::Lhs)]);
+        assert_eq!(arguments(&world, op), (b, a));
+    }
+
+    #[test]
+    fn unslotted_patients_fill_free_slots() {
+        let (world, op, [a, b]) = resolve([None, Some(SdPatientSlot::Lhs)]);
+        assert_eq!(arguments(&world, op), (b, a));
+
+        let (world, op, [a, b]) = resolve([Some(SdPatientSlot::Rhs), None]);
+        assert_eq!(arguments(&world, op), (b, a));
+    }
+
+    #[test]
+    fn duplicate_slot_keeps_both_patients() {
+        let (world, op, [a, b]) = resolve([Some(SdPatientSlot::Rhs), Some(SdPatientSlot::Rhs)]);
+        let (lhs, rhs) = arguments(&world, op);
+        assert!((lhs, rhs) == (a, b) || (lhs, rhs) == (b, a));
+    }
+
+    #[test]
+    fn root_blend_gets_blender_units() {
+        let (world, op, _) = resolve([None, None]);
+        assert_eq!(world.get::<SdUnits>(op), Some(&SdUnits::Blender));
     }
 }
@@ -0,0 +1,32 @@
+use bevy::prelude::*;
+
+use crate::engine::object::SdShapeUniform;
+
+// NOTE: Shape type id reserved for instances, it must match `SD_INSTANCE_TYPE_ID` in types.wgsl
+pub const SD_INSTANCE_TYPE_ID: u8 = 0xFF;
+
+// Marks an SdBlend root as a prefab: it is not rendered on its own,
+// only through the SdInstance leaves referencing it
+#[derive(Component, Reflect, Default, Debug, Clone, Copy)]
+#[reflect(Component, Default)]
+pub struct SdPrefab;
+
+// A leaf that evaluates the `prefab` tree with its sample point moved by `transform`.
+// Like an SdShape it must be operated by an SdBlend to be rendered.
+// WARN: Instances inside a prefab are not supported and evaluate to empty space
+#[derive(Component, Reflect, Debug, Clone, Copy)]
+#[require(Name::new("SdInstance"))]
+#[reflect(Component)]
+pub struct SdInstance {
+    #[entities]
+    pub prefab: Entity,
+    pub transform: Transform,
+}
+
+impl SdShapeUniform {
+    // Packs the prefab program range the same way as a shape's field data range
+    #[inline]
+    pub fn instance(op_start: usize, op_len: usize) -> Self {
+        Self::new(SD_INSTANCE_TYPE_ID, op_start, op_len)
+    }
+}
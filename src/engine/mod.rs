@@ -6,47 +6,73 @@ use bevy::render::{Render, RenderStartup, RenderSystems};
 use bevy::shader::load_shader_library;
 use bevy::{
     core_pipeline::core_3d::graph::{Core3d, Node3d},
+    pbr::graph::NodePbr,
     render::{
         RenderApp,
         extract_component::{ExtractComponentPlugin, UniformComponentPlugin},
         render_graph::ViewNodeRunner,
     },
 };
-use camera::RayMarchCamera;
+use blend_registry::{SdBlendRegistry, SdCustomBlendId, update_custom_blend_shaders};
+use camera::{RayMarchCamera, RayMarchCameraUniform, SdShadowMode};
+use compile::{SdCompiledMaps, SdMapMode};
 use hierarchy::{SdOperatedBy, SdOperatingOn};
+use instance::{SdInstance, SdPrefab};
 use nodes::RayMarchEngineNode;
 use object::{SdMaterial, SdMod, SdShape};
+use mod_registry::{SdCustomModId, SdModRegistry, update_custom_mod_shaders};
 use op::SdBlend;
+use shape_registry::{
+    SdCustomShape, SdCustomShapeId, SdShapeRegistry, update_custom_shape_shaders,
+};
+use units::SdUnits;
+use volume::SdVolume;
 
 use crate::engine::blit_pass::{BlitNode, init_raymarch_blit_pipeline};
 use crate::engine::buffer::RayMarchBuffer;
 use crate::engine::object::SdModStack;
 use crate::engine::op::SdIndex;
-use crate::engine::pipeline::init_raymarch_compute_pipeline;
+use crate::engine::pipeline::{init_raymarch_compute_pipeline, queue_raymarch_pipelines};
+use crate::engine::shadow_caster::ShadowCasterNode;
 use crate::engine::prepare::{
     prepare_raymarch_bind_group, prepare_raymarch_buffer, prepare_raymarch_textures,
 };
 use bevy::render::render_graph::RenderGraphExt;
 
 #[cfg(feature = "skein")]
-use hierarchy::InitSkeinSdRelationShip;
+use hierarchy::{InitSkeinSdRelationShip, SdPatientSlot, init_skein_sd_relationships};
 
 mod blit_pass;
 mod nodes;
 mod pipeline;
+mod shadow_caster;
 
+pub mod blend_registry;
 pub mod buffer;
+pub mod builder;
 pub mod camera;
+pub mod compile;
 pub mod hierarchy;
+pub mod instance;
+pub mod mod_registry;
 pub mod object;
 pub mod op;
 pub mod prepare;
 pub mod prepass;
+pub mod shape_registry;
+pub mod units;
+pub mod volume;
 
 const RAY_MARCH_COMPUTE_PASS_HANDLE: Handle<Shader> =
     uuid_handle!("ca4a5dbf-4da9-4779-bcdc-dd3186088e08");
 const RAY_MARCH_BLIT_PASS_HANDLE: Handle<Shader> =
     uuid_handle!("691fef51-0ad4-4131-81c6-f71e674505ab");
+pub(crate) const RAY_MARCH_SHAPES_HANDLE: Handle<Shader> =
+    uuid_handle!("3b7d0c52-8f0e-4d6a-9a43-5be1f2c7e8d4");
+pub(crate) const RAY_MARCH_MODS_HANDLE: Handle<Shader> =
+    uuid_handle!("9e2a6f13-4c8b-4b7e-a1d5-0f6c3e82b4a9");
+pub(crate) const RAY_MARCH_BLENDS_HANDLE: Handle<Shader> =
+    uuid_handle!("5d8c1b27-e3a4-4f96-b07d-2a9e6c41f853");
 
 const WORKGROUP_SIZE: u32 = 8;
 
@@ -57,7 +83,7 @@ impl Plugin for RayMarchEnginePlugin {
         load_internal_asset!(
             app,
             RAY_MARCH_COMPUTE_PASS_HANDLE,
-            "../shaders/ray_march.wgsl",
+            "../shaders/ray_march_pass.wgsl",
             Shader::from_wgsl
         );
 
@@ -68,35 +94,106 @@ impl Plugin for RayMarchEnginePlugin {
             Shader::from_wgsl
         );
 
+        // Generated from the EnumVariantGpuFields derive so the shader dispatch follows SdShape
+        app.world_mut()
+            .resource_mut::<Assets<Shader>>()
+            .insert(
+                RAY_MARCH_SHAPES_HANDLE.id(),
+                Shader::from_wgsl(
+                    object::gpu_shapes_wgsl(&SdShapeRegistry::default()),
+                    "bevy_sdf/shapes.wgsl",
+                ),
+            )
+            .unwrap();
+        app.world_mut()
+            .resource_mut::<Assets<Shader>>()
+            .insert(
+                RAY_MARCH_MODS_HANDLE.id(),
+                Shader::from_wgsl(
+                    SdModRegistry::default().gpu_mods_wgsl(),
+                    "bevy_sdf/mods.wgsl",
+                ),
+            )
+            .unwrap();
+        app.world_mut()
+            .resource_mut::<Assets<Shader>>()
+            .insert(
+                RAY_MARCH_BLENDS_HANDLE.id(),
+                Shader::from_wgsl(
+                    SdBlendRegistry::default().gpu_blends_wgsl(),
+                    "bevy_sdf/blends.wgsl",
+                ),
+            )
+            .unwrap();
+
         load_shader_library!(app, "../shaders/bindings.wgsl");
         load_shader_library!(app, "../shaders/utils.wgsl");
         load_shader_library!(app, "../shaders/types.wgsl");
         load_shader_library!(app, "../shaders/selectors.wgsl");
+        load_shader_library!(app, "../shaders/ray_march.wgsl");
 
         app.add_systems(
             Update,
-            (prepare_raymarch_buffer.run_if(
+            (
+                update_custom_shape_shaders.run_if(resource_changed::<SdShapeRegistry>),
+                update_custom_mod_shaders.run_if(resource_changed::<SdModRegistry>),
+                update_custom_blend_shaders.run_if(resource_changed::<SdBlendRegistry>),
+                read_material_asset_events.before(prepare_raymarch_buffer),
+                prepare_raymarch_buffer.run_if(
                 not(resource_exists::<RayMarchBuffer>)
                     .or(ray_march_object_buffer_needs_update)
-                    .or(ray_march_operator_buffer_needs_update),
-            ),),
+                    .or(ray_march_custom_shape_buffer_needs_update)
+                    .or(ray_march_material_assets_need_update)
+                    .or(resource_changed::<SdShapeRegistry>)
+                    .or(resource_changed::<SdModRegistry>)
+                    .or(resource_changed::<SdBlendRegistry>)
+                    .or(resource_changed::<SdMapMode>)
+                    .or(ray_march_operator_buffer_needs_update)
+                    .or(ray_march_instance_buffer_needs_update)
+                    .or(ray_march_volume_buffer_needs_update)
+                    .or(resource_changed::<SdUnits>)
+                    .or(ray_march_units_need_update),
+                ),
+            ),
         );
 
         app.add_plugins((
             ExtractComponentPlugin::<RayMarchCamera>::default(),
-            UniformComponentPlugin::<RayMarchCamera>::default(),
+            UniformComponentPlugin::<RayMarchCameraUniform>::default(),
             ExtractResourcePlugin::<RayMarchBuffer>::default(),
         ))
         .register_type::<RayMarchCamera>()
+        .register_type::<SdShadowMode>()
         .register_type::<SdShape>()
         .register_type::<SdBlend>()
         .register_type::<SdMod>()
         .register_type::<SdModStack>()
         .register_type::<SdIndex>()
+        .register_type::<SdInstance>()
+        .register_type::<SdPrefab>()
+        .register_type::<SdVolume>()
+        .register_type::<SdUnits>()
+        .init_resource::<SdUnits>()
+        .register_type::<SdCustomShape>()
+        .register_type::<SdCustomShapeId>()
+        .init_resource::<SdShapeRegistry>()
+        .register_type::<SdCustomModId>()
+        .init_resource::<SdModRegistry>()
+        .register_type::<SdCustomBlendId>()
+        .init_resource::<SdBlendRegistry>()
+        .register_type::<SdMapMode>()
+        .init_resource::<SdMapMode>()
+        .init_resource::<SdCompiledMaps>()
+        .init_resource::<SdMaterialAssetsChanged>()
         .register_type::<SdMaterial>();
 
         #[cfg(feature = "skein")]
-        app.register_type::<InitSkeinSdRelationShip>();
+        app.register_type::<InitSkeinSdRelationShip>()
+            .register_type::<SdPatientSlot>()
+            .add_systems(
+                Update,
+                init_skein_sd_relationships.before(prepare_raymarch_buffer),
+            );
 
         let Some(render_app) = app.get_sub_app_mut(RenderApp) else {
             return;
@@ -113,6 +210,9 @@ impl Plugin for RayMarchEnginePlugin {
                     prepare_raymarch_bind_group
                         .in_set(RenderSystems::PrepareBindGroups)
                         .run_if(resource_exists::<RayMarchBuffer>),
+                    queue_raymarch_pipelines
+                        .in_set(RenderSystems::Queue)
+                        .run_if(resource_exists::<RayMarchBuffer>),
                 ),
             )
             .add_render_graph_node::<ViewNodeRunner<RayMarchEngineNode>>(
@@ -121,6 +221,20 @@ impl Plugin for RayMarchEnginePlugin {
             )
             .add_render_graph_edges(Core3d, (Node3d::MainOpaquePass, RayMarchPass::ComputePass));
 
+        render_app
+            .add_render_graph_node::<ViewNodeRunner<ShadowCasterNode>>(
+                Core3d,
+                RayMarchPass::ShadowCasterPass,
+            )
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    NodePbr::LateShadowPass,
+                    RayMarchPass::ShadowCasterPass,
+                    Node3d::StartMainPass,
+                ),
+            );
+
         render_app
             .add_systems(RenderStartup, init_raymarch_blit_pipeline)
             .add_render_graph_node::<ViewNodeRunner<BlitNode>>(Core3d, RayMarchPass::BlitPass)
@@ -137,10 +251,12 @@ impl Plugin for RayMarchEnginePlugin {
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 pub enum RayMarchPass {
+    ShadowCasterPass,
     ComputePass,
     BlitPass,
 }
 
+#[allow(clippy::type_complexity)]
 fn ray_march_operator_buffer_needs_update(
     check_op_query: Query<
         (),
@@ -155,6 +271,7 @@ fn ray_march_operator_buffer_needs_update(
     !check_op_query.is_empty()
 }
 
+#[allow(clippy::type_complexity)]
 fn ray_march_object_buffer_needs_update(
     check_object_query: Query<
         (),
@@ -162,7 +279,7 @@ fn ray_march_object_buffer_needs_update(
             With<SdShape>,
             With<SdModStack>,
             With<GlobalTransform>,
-            With<SdOperatedBy>,
+            Or<(With<SdOperatedBy>, With<SdVolume>)>,
             Or<(
                 Changed<SdShape>,
                 Changed<SdModStack>,
@@ -175,3 +292,74 @@ fn ray_march_object_buffer_needs_update(
 ) -> bool {
     !check_object_query.is_empty()
 }
+
+#[allow(clippy::type_complexity)]
+fn ray_march_custom_shape_buffer_needs_update(
+    check_custom_shape_query: Query<
+        (),
+        (
+            With<SdCustomShape>,
+            Or<(With<SdOperatedBy>, With<SdVolume>)>,
+            Or<(
+                Changed<SdCustomShape>,
+                Changed<SdModStack>,
+                Changed<GlobalTransform>,
+                Changed<SdMaterial>,
+                Changed<MeshMaterial3d<StandardMaterial>>,
+            )>,
+        ),
+    >,
+) -> bool {
+    !check_custom_shape_query.is_empty()
+}
+
+// Set for the frames where a StandardMaterial of an SDF was loaded or edited
+#[derive(Resource, Default)]
+struct SdMaterialAssetsChanged(bool);
+
+// Editing a StandardMaterial asset leaves the MeshMaterial3d untouched, only the asset event tells.
+// NOTE: The events are read every frame here, a run condition in the `or` chain is skipped
+// once an earlier one is true and would see the same events again on the next frame
+#[allow(clippy::type_complexity)]
+fn read_material_asset_events(
+    mut material_events: MessageReader<AssetEvent<StandardMaterial>>,
+    check_material_query: Query<
+        &MeshMaterial3d<StandardMaterial>,
+        Or<(With<SdOperatedBy>, With<SdVolume>)>,
+    >,
+    mut changed: ResMut<SdMaterialAssetsChanged>,
+) {
+    let updated: Vec<_> = material_events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Added { id }
+            | AssetEvent::Modified { id }
+            | AssetEvent::LoadedWithDependencies { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    changed.0 = !updated.is_empty()
+        && check_material_query
+            .iter()
+            .any(|material| updated.contains(&material.id()));
+}
+
+fn ray_march_material_assets_need_update(changed: Res<SdMaterialAssetsChanged>) -> bool {
+    changed.0
+}
+
+#[allow(clippy::type_complexity)]
+fn ray_march_instance_buffer_needs_update(
+    check_instance_query: Query<(), Or<(Changed<SdInstance>, Changed<SdPrefab>)>>,
+) -> bool {
+    !check_instance_query.is_empty()
+}
+
+fn ray_march_volume_buffer_needs_update(check_volume_query: Query<(), Changed<SdVolume>>) -> bool {
+    !check_volume_query.is_empty()
+}
+
+fn ray_march_units_need_update(check_units_query: Query<(), Changed<SdUnits>>) -> bool {
+    !check_units_query.is_empty()
+}
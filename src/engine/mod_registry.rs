@@ -0,0 +1,157 @@
+use bevy::prelude::*;
+
+use crate::engine::RAY_MARCH_MODS_HANDLE;
+
+// NOTE: Registered modifiers get the type ids from here, the SdMod variants must stay below it
+pub const SD_CUSTOM_MOD_TYPE_ID_START: u32 = 0x80;
+
+// Type id of a modifier added to the SdModRegistry
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SdCustomModId(pub u32);
+
+#[derive(Clone)]
+pub struct SdCustomModDescriptor {
+    // Name of the WGSL function, it must be unique
+    pub name: String,
+    // WGSL source defining `fn <name>(p: vec3f, data_index: u32) -> vec3f`,
+    // the payload is read with `sd_field_data[data_index + i]`
+    pub wgsl: String,
+    pub payload_size: usize,
+}
+
+// Domain modifiers defined by the app, used in an SdModStack with `SdMod::Custom`.
+// Modifiers can only be added so the type ids given out stay valid.
+#[derive(Resource, Default, Clone)]
+pub struct SdModRegistry {
+    mods: Vec<SdCustomModDescriptor>,
+}
+
+impl SdModRegistry {
+    // The WGSL source can `#import bevy_sdf::bindings::sd_field_data` and anything from `bevy_sdf::utils`
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        wgsl: impl Into<String>,
+        payload_size: usize,
+    ) -> SdCustomModId {
+        let descriptor = SdCustomModDescriptor {
+            name: name.into(),
+            wgsl: wgsl.into(),
+            payload_size,
+        };
+        assert!(
+            self.mods.iter().all(|m| m.name != descriptor.name),
+            "Custom modifier {} is already registered",
+            descriptor.name
+        );
+
+        self.mods.push(descriptor);
+        SdCustomModId(SD_CUSTOM_MOD_TYPE_ID_START + self.mods.len() as u32 - 1)
+    }
+
+    pub fn get(&self, id: SdCustomModId) -> Option<&SdCustomModDescriptor> {
+        self.mods
+            .get(id.0.checked_sub(SD_CUSTOM_MOD_TYPE_ID_START)? as usize)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SdCustomModId, &SdCustomModDescriptor)> {
+        self.mods
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (SdCustomModId(SD_CUSTOM_MOD_TYPE_ID_START + i as u32), m))
+    }
+
+    // Source of the `bevy_sdf::mods` shader library, `apply_mod` falls back on it for unknown type ids
+    pub(crate) fn gpu_mods_wgsl(&self) -> String {
+        let imports: String = self
+            .mods
+            .iter()
+            .map(|m| format!("#import bevy_sdf::custom_mods::{0}::{0}\n", m.name))
+            .collect();
+        let arms: String = self
+            .iter()
+            .map(|(id, m)| {
+                format!(
+                    "        case {}u {{\n            return {}(p, modifier.data_index);\n        }}\n",
+                    id.0, m.name
+                )
+            })
+            .collect();
+
+        format!(
+            "#define_import_path bevy_sdf::mods
+
+#import bevy_sdf::types::SdMod
+{imports}
+fn apply_custom_mod(p: vec3f, modifier: SdMod) -> vec3f {{
+    switch modifier.type_id {{
+{arms}        default {{
+            return p;
+        }}
+    }}
+}}
+"
+        )
+    }
+}
+
+// Every registered modifier is its own shader library imported by the generated `bevy_sdf::mods`
+pub(crate) fn update_custom_mod_shaders(
+    registry: Res<SdModRegistry>,
+    mut shaders: ResMut<Assets<Shader>>,
+    mut handles: Local<Vec<Handle<Shader>>>,
+) {
+    // The registry only grows, the libraries already added are kept as they are
+    for modifier in registry.mods.iter().skip(handles.len()) {
+        handles.push(shaders.add(Shader::from_wgsl(
+            format!(
+                "#define_import_path bevy_sdf::custom_mods::{}\n\n{}",
+                modifier.name, modifier.wgsl
+            ),
+            format!("bevy_sdf/custom_mods/{}.wgsl", modifier.name),
+        )));
+    }
+
+    shaders
+        .insert(
+            RAY_MARCH_MODS_HANDLE.id(),
+            Shader::from_wgsl(registry.gpu_mods_wgsl(), "bevy_sdf/mods.wgsl"),
+        )
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_start_after_builtin_mods() {
+        let mut registry = SdModRegistry::default();
+        let wave = registry.register("wave", "", 2);
+        let swirl = registry.register("swirl", "", 1);
+        assert_eq!(wave, SdCustomModId(SD_CUSTOM_MOD_TYPE_ID_START));
+        assert_eq!(swirl, SdCustomModId(SD_CUSTOM_MOD_TYPE_ID_START + 1));
+        assert_eq!(registry.get(swirl).unwrap().payload_size, 1);
+        assert!(registry.get(SdCustomModId(0)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Custom modifier wave is already registered")]
+    fn duplicate_names_panic() {
+        let mut registry = SdModRegistry::default();
+        registry.register("wave", "", 2);
+        registry.register("wave", "", 2);
+    }
+
+    #[test]
+    fn dispatch_passes_the_data_index() {
+        let mut registry = SdModRegistry::default();
+        let id = registry.register("wave", "", 2);
+        let wgsl = registry.gpu_mods_wgsl();
+        assert!(wgsl.contains("#import bevy_sdf::custom_mods::wave::wave\n"));
+        assert!(wgsl.contains(&format!(
+            "case {}u {{\n            return wave(p, modifier.data_index);",
+            id.0
+        )));
+    }
+}
@@ -1,9 +1,14 @@
 use bevy::ecs::query::QueryItem;
 use bevy::prelude::*;
+use bevy::light::ShadowFilteringMethod;
 use bevy::render::camera::ExtractedCamera;
+use bevy::render::render_asset::RenderAssets;
 use bevy::render::render_graph::NodeRunError;
-use bevy::render::render_resource::{BindGroup, ComputePassDescriptor, PipelineCache};
+use bevy::render::render_resource::{
+    BindGroup, BindGroupEntries, ComputePassDescriptor, PipelineCache,
+};
 use bevy::render::renderer::RenderContext;
+use bevy::render::texture::GpuImage;
 use bevy::{
     ecs::system::lifetimeless::Read,
     pbr::ViewLightsUniformOffset,
@@ -14,15 +19,17 @@ use bevy::{
     },
 };
 
-use super::pipeline::RayMarchEnginePipeline;
-use super::{RayMarchCamera, WORKGROUP_SIZE};
+use super::buffer::{RayMarchBuffer, SD_MIN_PREFAB_OPS};
+use super::pipeline::{RayMarchEnginePipeline, RayMarchPipelineKey};
+use super::camera::{RayMarchCamera, RayMarchCameraUniform, RayMarchEnvironmentMap};
+use super::prepass::RayMarchPrepass;
+use super::WORKGROUP_SIZE;
 
 #[derive(Resource)]
 pub struct RayMarchEngineBindGroup {
     pub common_bind_group: BindGroup,
     pub texture_bind_group: BindGroup,
     pub storage_bind_group: BindGroup,
-    pub prepass_bind_group: BindGroup,
 }
 
 #[derive(Default)]
@@ -33,9 +40,12 @@ impl ViewNode for RayMarchEngineNode {
         Read<ViewTarget>,
         Read<ExtractedCamera>,
         Read<RayMarchCamera>,
-        Read<DynamicUniformIndex<RayMarchCamera>>,
+        Option<Read<ShadowFilteringMethod>>,
+        Read<RayMarchEnvironmentMap>,
+        Read<DynamicUniformIndex<RayMarchCameraUniform>>,
         Read<ViewUniformOffset>,
         Read<ViewLightsUniformOffset>,
+        Read<RayMarchPrepass>,
     );
 
     fn run(
@@ -43,12 +53,15 @@ impl ViewNode for RayMarchEngineNode {
         _graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
         (
-            _view_target,
+            view_target,
             camera,
             ray_march_settings,
+            shadow_filter,
+            environment_map,
             settings_index,
             view_uniform_offset,
             view_lights_uniform_offset,
+            raymarch_prepass,
         ): QueryItem<Self::ViewQuery>,
         world: &World,
     ) -> Result<(), NodeRunError> {
@@ -60,17 +73,59 @@ impl ViewNode for RayMarchEngineNode {
         let ray_march_pipeline = world.resource::<RayMarchEnginePipeline>();
         let bind_group = world.resource::<RayMarchEngineBindGroup>();
 
+        let environment_map = environment_map
+            .gpu_images(world.resource::<RenderAssets<GpuImage>>())
+            .is_some();
+        let buffer = world.get_resource::<RayMarchBuffer>();
+        let volumes = buffer.is_some_and(|buffer| buffer.volumes);
+        let transmission = buffer.is_some_and(|buffer| buffer.transmission);
+        let max_prefab_ops = buffer.map_or(SD_MIN_PREFAB_OPS, |buffer| buffer.max_prefab_ops);
+        let pipeline = |hash| {
+            let key = RayMarchPipelineKey::new(
+                ray_march_settings,
+                shadow_filter,
+                environment_map,
+                volumes,
+                transmission,
+                max_prefab_ops,
+                hash,
+            );
+            ray_march_pipeline
+                .raymarch_pipelines
+                .get(&key)
+                .and_then(|&id| pipeline_cache.get_compute_pipeline(id))
+        };
+
         let (Some(march_pipeline), Some(scale_pipeline)) = (
-            pipeline_cache.get_compute_pipeline(ray_march_pipeline.compute_raymarch_pipeline),
+            pipeline(None),
             pipeline_cache.get_compute_pipeline(ray_march_pipeline.compute_mask_pipeline),
         ) else {
             return Ok(());
         };
 
+        // The interpreter keeps rendering until the compiled map pipeline is ready
+        let march_pipeline = buffer
+            .and_then(|buffer| buffer.compiled_map.as_ref())
+            .and_then(|map| pipeline(Some(map.hash)))
+            .unwrap_or(march_pipeline);
+
         let Some(viewport) = camera.physical_viewport_size else {
             return Ok(());
         };
 
+        // Made for every view, the main texture changes between frames
+        let prepass_bind_group = render_context.render_device().create_bind_group(
+            "marcher_prepass_bind_group",
+            &pipeline_cache.get_bind_group_layout(&ray_march_pipeline.prepass_layout),
+            &BindGroupEntries::sequential((
+                &raymarch_prepass.depth,
+                &raymarch_prepass.normal,
+                &raymarch_prepass.mask,
+                &raymarch_prepass.output,
+                view_target.main_texture_view(),
+            )),
+        );
+
         let mut pass =
             render_context
                 .command_encoder()
@@ -89,7 +144,7 @@ impl ViewNode for RayMarchEngineNode {
         );
         pass.set_bind_group(1, &bind_group.texture_bind_group, &[settings_index.index()]);
         pass.set_bind_group(2, &bind_group.storage_bind_group, &[]);
-        pass.set_bind_group(3, &bind_group.prepass_bind_group, &[]);
+        pass.set_bind_group(3, &prepass_bind_group, &[]);
 
         pass.set_pipeline(march_pipeline);
         pass.dispatch_workgroups(
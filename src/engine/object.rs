@@ -1,13 +1,18 @@
 use bevy::math::VectorSpace;
+use bevy::platform::collections::HashMap;
 use bevy::prelude::*;
 use bevy::render::render_resource::ShaderType;
 use bevy_sdf_klown_derive::EnumVariantGpuFields;
+use half::f16;
 use std::mem::transmute;
 
+use crate::engine::{mod_registry::SdCustomModId, shape_registry::SdShapeRegistry};
+
 #[derive(ShaderType, Clone, Copy)]
 pub struct SdObjectUniform {
     pub shape: SdShapeUniform,
-    pub material: SdMaterialUniform,
+    // Index in sd_materials
+    pub material_index: u32,
     pub modifier_stack: SdModStackUniform,
     pub transform: SdTransformUniform,
 }
@@ -21,10 +26,15 @@ pub struct SdObject {
 }
 
 impl SdObject {
-    pub fn uniform(&self, start_mod_index: usize, start_shape_index: usize) -> SdObjectUniform {
+    pub fn uniform(
+        &self,
+        start_mod_index: usize,
+        start_shape_index: usize,
+        materials: &mut SdMaterialTable,
+    ) -> SdObjectUniform {
         SdObjectUniform {
             shape: self.shape.uniform(start_shape_index),
-            material: self.material.uniform(),
+            material_index: materials.insert(self.material),
             modifier_stack: self.modifier_stack.clone().uniform(start_mod_index),
             transform: self.transform.uniform(),
         }
@@ -36,72 +46,104 @@ impl SdObject {
 #[derive(Reflect, Component, Debug, Copy, Clone, EnumVariantGpuFields)]
 #[require(Name::new("SdObject"), SdModStack, Transform, GlobalTransform)]
 #[reflect(Component)]
+#[gpu(import = "bevy_sdf::utils")]
+// NOTE: Lengths are marked with `#[gpu(scale)]` so SdUnits converts them, angles, counts and normals are sent as is
 pub enum SdShape {
     Sphere {
+        #[gpu(scale)]
         radius: f32,
     },
     Ellipsoid {
+        #[gpu(scale)]
         radius: Vec3,
     },
     Box {
+        #[gpu(scale)]
         bounds: Vec3,
     },
     RoundBox {
+        #[gpu(scale)]
         bounds: Vec3,
+        #[gpu(scale)]
         radius: f32,
     },
     BoxFrame {
+        #[gpu(scale)]
         bounds: Vec3,
+        #[gpu(scale)]
         edge: f32,
     },
     Gyroid {
         height: f32,
     },
     Torus {
+        #[gpu(scale)]
         major_radius: f32,
+        #[gpu(scale)]
         minor_radius: f32,
     },
     CappedTorus {
+        #[gpu(scale)]
         major_radius: f32,
+        #[gpu(scale)]
         minor_radius: f32,
         sincos: Vec2,
     },
     Link {
+        #[gpu(scale)]
         major_radius: f32,
+        #[gpu(scale)]
         minor_radius: f32,
+        #[gpu(scale)]
         length: f32,
     },
     VerticalCapsule {
+        #[gpu(scale)]
         height: f32,
+        #[gpu(scale)]
         radius: f32,
     },
     Capsule {
+        #[gpu(scale)]
         a: Vec3,
+        #[gpu(scale)]
         b: Vec3,
+        #[gpu(scale)]
         radius: f32,
     },
     Cylinder {
+        #[gpu(scale)]
         a: Vec3,
+        #[gpu(scale)]
         b: Vec3,
+        #[gpu(scale)]
         radius: f32,
     },
     VerticalCylinder {
+        #[gpu(scale)]
         height: f32,
+        #[gpu(scale)]
         radius: f32,
     },
     RoundedCylinder {
+        #[gpu(scale)]
         height: f32,
+        #[gpu(scale)]
         radius: f32,
+        #[gpu(scale)]
         edge_radius: f32,
     },
     InfiniteCylinder {
+        #[gpu(scale)]
         center: Vec3,
     },
     Cone {
+        #[gpu(scale)]
         height: f32,
         sincos: Vec2,
     },
     ConeBound {
+        #[gpu(scale)]
         height: f32,
         sincos: Vec2,
     },
@@ -109,99 +151,187 @@ pub enum SdShape {
         sincos: Vec2,
     },
     CappedVerticalCone {
+        #[gpu(scale)]
         height: f32,
+        #[gpu(scale)]
         r1: f32,
+        #[gpu(scale)]
         r2: f32,
     },
     CappedCone {
+        #[gpu(scale)]
         a: Vec3,
+        #[gpu(scale)]
         b: Vec3,
+        #[gpu(scale)]
         ra: f32,
+        #[gpu(scale)]
         rb: f32,
     },
     RoundVerticalCone {
+        #[gpu(scale)]
         height: f32,
+        #[gpu(scale)]
         r1: f32,
+        #[gpu(scale)]
         r2: f32,
     },
     RoundCone {
+        #[gpu(scale)]
         a: Vec3,
+        #[gpu(scale)]
         b: Vec3,
+        #[gpu(scale)]
         r1: f32,
+        #[gpu(scale)]
         r2: f32,
     },
     SolidAngle {
         sincos: Vec2,
+        #[gpu(scale)]
         radius: f32,
     },
     Plane {
         normal: Vec3,
+        #[gpu(scale)]
         height: f32,
     },
     Octahedron {
+        #[gpu(scale)]
         size: f32,
     },
     OctahedronBound {
+        #[gpu(scale)]
         size: f32,
     },
     Pyramid {
+        #[gpu(scale)]
         height: f32,
     },
     HexPrism {
+        #[gpu(scale)]
         bound: Vec2,
     },
     TriPrism {
+        #[gpu(scale)]
         bound: Vec2,
     },
+    #[gpu(wgsl = "udTriangle")]
     Triangle {
+        #[gpu(scale)]
         a: Vec3,
+        #[gpu(scale)]
         b: Vec3,
+        #[gpu(scale)]
         c: Vec3,
     },
+    #[gpu(wgsl = "sdScaledBunny")]
     Bunny {
+        #[gpu(scale)]
         s: f32,
     },
+    #[gpu(wgsl = "sdScaledMandelbulb")]
     MandelBulb {
+        #[gpu(scale)]
         scale: f32,
         iter: f32,
         expo: f32,
         b_offset: f32,
     },
+    #[gpu(wgsl = "sdScaledJuliaQuaternion")]
     JuliaQuaternion {
+        #[gpu(scale)]
         scale: f32,
         iter: f32,
     },
+    #[gpu(wgsl = "sdScaledMengerSponge")]
     MengerSponge {
+        #[gpu(scale)]
         scale: f32,
         iter: f32,
     },
 }
 
+// Source of the `bevy_sdf::shapes` shader library: the field offsets, the type ids
+// and the `select_shape_dist` dispatch, generated from the SdShape enum and the registered shapes.
+// NOTE: An unknown type id is empty space, it used to be drawn as a sphere. Custom shapes missing
+// from the registry never get here, `prepare_raymarch_buffer` warns and sends them as empty leaves
+pub(crate) fn gpu_shapes_wgsl(registry: &SdShapeRegistry) -> String {
+    format!(
+        "#define_import_path bevy_sdf::shapes
+
+#import bevy_sdf::bindings::sd_field_data
+{}{}
+{}
+fn select_shape_dist(p: vec3f, type_id: u32, data_index: u32) -> f32 {{
+    switch type_id {{
+{}{}        default {{
+            return 1e10;
+        }}
+    }}
+}}
+",
+        SdShape::gpu_imports_wgsl(),
+        registry.imports_wgsl(),
+        SdShape::gpu_layout_wgsl(),
+        SdShape::gpu_select_arms_wgsl(),
+        registry.select_arms_wgsl(),
+    )
+}
+
 #[repr(C)]
 #[derive(ShaderType, Clone, Copy)]
 pub struct SdShapeUniform {
     pub type_id_index_len: u32,
 }
 
-impl SdShape {
+// NOTE: Shape type id of leaves that evaluate to nothing, it must match `SD_EMPTY_TYPE_ID` in types.wgsl.
+// It is the first of the ids reserved for leaves that are not shapes.
+pub const SD_EMPTY_TYPE_ID: u8 = 0xFD;
+
+impl SdShapeUniform {
     #[inline]
-    pub fn uniform(self, index: usize) -> SdShapeUniform {
-        let self_bytes: [u8; 40] = unsafe { transmute(self) };
+    pub fn type_id(&self) -> u32 {
+        self.type_id_index_len.to_ne_bytes()[0] as u32
+    }
+
+    // Packs a u8 type id, the u16 index of the shape data and its u8 length
+    #[inline]
+    pub fn new(type_id: u8, index: usize, len: usize) -> Self {
         let index_bytes: [u8; 2] = (index as u16).to_ne_bytes();
-        let len_byte: u8 = self.gpu_field_count() as u8;
+        Self {
+            type_id_index_len: u32::from_ne_bytes([
+                type_id,
+                index_bytes[0],
+                index_bytes[1],
+                len as u8,
+            ]),
+        }
+    }
 
-        unsafe { transmute([self_bytes[0], index_bytes[0], index_bytes[1], len_byte]) }
+    // A leaf that is not a valid shape, it keeps the object indices aligned
+    #[inline]
+    pub fn empty() -> Self {
+        Self::new(SD_EMPTY_TYPE_ID, 0, 0)
+    }
+}
+
+impl SdShape {
+    #[inline]
+    pub fn uniform(self, index: usize) -> SdShapeUniform {
+        SdShapeUniform::new(self.gpu_type_id() as u8, index, self.gpu_field_count())
     }
 }
 
 #[derive(ShaderType, Default, Clone, Debug, Copy)]
 pub struct SdModUniform {
     pub type_id: u32,
+    // Payload of a Custom modifier in the field data, it fills the padding before `data`
+    pub data_index: u32,
     pub data: Vec4,
 }
 
-#[repr(u32)]
-#[derive(Reflect, Debug, Clone, Copy)]
+#[derive(Reflect, Debug, Clone)]
 #[reflect(Default)]
 pub enum SdMod {
     Translate { t: Vec3 },
@@ -220,6 +350,8 @@ pub enum SdMod {
     InfArray { c: Vec3 },
     LimArray { c: f32, lim: Vec3 },
     Elongate { h: Vec3 },
+    // A modifier of the SdModRegistry, `data` is its payload
+    Custom { id: SdCustomModId, data: Vec<f32> },
 }
 
 impl Default for SdMod {
@@ -229,13 +361,32 @@ impl Default for SdMod {
 }
 
 impl SdMod {
+    // `data_index` is where the payload of a Custom modifier is in the field data
     #[inline]
-    pub fn uniform(self) -> SdModUniform {
-        let bytes: [u32; 5] = unsafe { transmute(self) };
-        let pack: Vec4 = unsafe { transmute([bytes[1], bytes[2], bytes[3], bytes[4]]) };
+    pub fn uniform(&self, data_index: usize) -> SdModUniform {
+        let (type_id, data) = match *self {
+            Self::Translate { t } => (0, t.extend(0.)),
+            Self::OrthogonalRotateX => (1, Vec4::ZERO),
+            Self::OrthogonalRotateY => (2, Vec4::ZERO),
+            Self::OrthogonalRotateZ => (3, Vec4::ZERO),
+            Self::RotateX { a } => (4, Vec4::new(a, 0., 0., 0.)),
+            Self::RotateY { a } => (5, Vec4::new(a, 0., 0., 0.)),
+            Self::RotateZ { a } => (6, Vec4::new(a, 0., 0., 0.)),
+            Self::RotateEuleur { a } => (7, a.extend(0.)),
+            Self::Twist { k } => (8, Vec4::new(k, 0., 0., 0.)),
+            Self::CheapBend { k } => (9, Vec4::new(k, 0., 0., 0.)),
+            Self::SymetryX => (10, Vec4::ZERO),
+            Self::SymetryY => (11, Vec4::ZERO),
+            Self::SymetryZ => (12, Vec4::ZERO),
+            Self::InfArray { c } => (13, c.extend(0.)),
+            Self::LimArray { c, lim } => (14, Vec4::new(c, lim.x, lim.y, lim.z)),
+            Self::Elongate { h } => (15, h.extend(0.)),
+            Self::Custom { id, .. } => (id.0, Vec4::ZERO),
+        };
         SdModUniform {
-            type_id: bytes[0],
-            data: pack,
+            type_id,
+            data_index: data_index as u32,
+            data,
         }
     }
 }
@@ -263,13 +414,41 @@ impl SdModStack {
     }
 }
 
-#[derive(Reflect, ShaderType, Clone, Copy)]
+// Same packing as WGSL `pack2x16float`, `a` goes in the low half
+#[inline]
+fn pack2x16float(a: f32, b: f32) -> u32 {
+    f16::from_f32(a).to_bits() as u32 | ((f16::from_f32(b).to_bits() as u32) << 16)
+}
+
+#[derive(Reflect, ShaderType, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SdMaterialUniform {
-    pub color: u32,
-    pub rough_fres_metal: u32,
-    pub sss_strength_radius: u32,
+    pub color_rg: u32,
+    pub color_ba: u32,
+    pub roughness_fresnel: u32,
+    pub metallic_sss_strength: u32,
+    pub sss_radius_rg: u32,
+    pub sss_radius_b: u32,
+    pub emissive_rg: u32,
+    pub emissive_b_exposure_weight: u32,
+    pub specular_tint_rg: u32,
+    pub specular_tint_b_reflectance: u32,
+    pub transmission_ior: u32,
+    // Beer-Lambert absorption coefficients per unit of distance inside the surface
+    pub absorption_rg: u32,
+    pub absorption_b: u32,
+    // Flags in the low half and the f16 alpha cutoff in the high half
+    pub flags_alpha_cutoff: u32,
 }
 
+// NOTE: Must match the SD_MATERIAL_FLAGS_* consts of types.wgsl
+pub const SD_MATERIAL_FLAGS_UNLIT: u32 = 1;
+pub const SD_MATERIAL_FLAGS_ALPHA_MODE_SHIFT: u32 = 1;
+pub const SD_MATERIAL_ALPHA_MODE_OPAQUE: u32 = 0;
+pub const SD_MATERIAL_ALPHA_MODE_MASK: u32 = 1;
+pub const SD_MATERIAL_ALPHA_MODE_BLEND: u32 = 2;
+pub const SD_MATERIAL_ALPHA_MODE_PREMULTIPLIED: u32 = 3;
+pub const SD_MATERIAL_ALPHA_MODE_ADD: u32 = 4;
+
 #[derive(Component, Reflect, Debug, Clone, Copy)]
 #[reflect(Component, Default)]
 pub struct SdMaterial {
@@ -279,6 +458,23 @@ pub struct SdMaterial {
     pub metallic: f32,
     pub sss_strength: f32,
     pub sss_radius: Color,
+    // Added after lighting, same units as StandardMaterial::emissive so it drives Bloom the same way
+    pub emissive: LinearRgba,
+    // How much the view exposure applies to the emissive, see StandardMaterial::emissive_exposure_weight
+    pub emissive_exposure_weight: f32,
+    // Specular intensity of dielectrics, 0.5 is a 4% reflectance like StandardMaterial
+    pub reflectance: f32,
+    pub specular_tint: Color,
+    // How much light refracts through the surface instead of being diffused, see StandardMaterial::specular_transmission
+    pub specular_transmission: f32,
+    pub ior: f32,
+    // Light going through the inside tends towards attenuation_color after attenuation_distance
+    pub attenuation_distance: f32,
+    pub attenuation_color: Color,
+    // Skips lighting, the surface shows its color as is
+    pub unlit: bool,
+    // WARN: Multiply is rendered like Blend and AlphaToCoverage like Mask(0.5)
+    pub alpha_mode: AlphaMode,
 }
 
 impl Default for SdMaterial {
@@ -290,6 +486,16 @@ impl Default for SdMaterial {
             metallic: 0.,
             sss_strength: 0.,
             sss_radius: LinearRgba::ZERO.into(),
+            emissive: LinearRgba::BLACK,
+            emissive_exposure_weight: 0.,
+            reflectance: 0.5,
+            specular_tint: Color::WHITE,
+            specular_transmission: 0.,
+            ior: 1.5,
+            attenuation_distance: f32::INFINITY,
+            attenuation_color: Color::WHITE,
+            unlit: false,
+            alpha_mode: AlphaMode::Opaque,
         }
     }
 }
@@ -297,12 +503,47 @@ impl Default for SdMaterial {
 impl SdMaterial {
     #[inline]
     pub fn uniform(self) -> SdMaterialUniform {
+        let color = self.color.to_linear();
+        let sss_radius = self.sss_radius.to_linear();
+        let specular_tint = self.specular_tint.to_linear();
+        // NOTE: Infinite distance gives no absorption, black is clamped so it stays finite
+        let absorption = |channel: f32| -channel.max(1e-4).ln() / self.attenuation_distance;
+        let attenuation_color = self.attenuation_color.to_linear();
+
+        let (alpha_mode, alpha_cutoff) = match self.alpha_mode {
+            AlphaMode::Opaque => (SD_MATERIAL_ALPHA_MODE_OPAQUE, 0.),
+            AlphaMode::Mask(cutoff) => (SD_MATERIAL_ALPHA_MODE_MASK, cutoff),
+            AlphaMode::AlphaToCoverage => (SD_MATERIAL_ALPHA_MODE_MASK, 0.5),
+            AlphaMode::Blend | AlphaMode::Multiply => (SD_MATERIAL_ALPHA_MODE_BLEND, 0.),
+            AlphaMode::Premultiplied => (SD_MATERIAL_ALPHA_MODE_PREMULTIPLIED, 0.),
+            AlphaMode::Add => (SD_MATERIAL_ALPHA_MODE_ADD, 0.),
+        };
+        let mut flags = alpha_mode << SD_MATERIAL_FLAGS_ALPHA_MODE_SHIFT;
+        if self.unlit {
+            flags |= SD_MATERIAL_FLAGS_UNLIT;
+        }
+
         SdMaterialUniform {
-            color: u32::from_ne_bytes(self.color.to_linear().to_u8_array()),
-            rough_fres_metal: u32::from_ne_bytes(
-                LinearRgba::new(self.roughness, self.fresnel, self.metallic, 0.).to_u8_array(),
+            color_rg: pack2x16float(color.red, color.green),
+            color_ba: pack2x16float(color.blue, color.alpha),
+            roughness_fresnel: pack2x16float(self.roughness, self.fresnel),
+            metallic_sss_strength: pack2x16float(self.metallic, self.sss_strength),
+            sss_radius_rg: pack2x16float(sss_radius.red, sss_radius.green),
+            sss_radius_b: pack2x16float(sss_radius.blue, 0.),
+            emissive_rg: pack2x16float(self.emissive.red, self.emissive.green),
+            emissive_b_exposure_weight: pack2x16float(
+                self.emissive.blue,
+                self.emissive_exposure_weight,
+            ),
+            specular_tint_rg: pack2x16float(specular_tint.red, specular_tint.green),
+            specular_tint_b_reflectance: pack2x16float(specular_tint.blue, self.reflectance),
+            transmission_ior: pack2x16float(self.specular_transmission, self.ior),
+            absorption_rg: pack2x16float(
+                absorption(attenuation_color.red),
+                absorption(attenuation_color.green),
             ),
-            sss_strength_radius: u32::from_ne_bytes(self.sss_radius.to_linear().to_u8_array()),
+            absorption_b: pack2x16float(absorption(attenuation_color.blue), 0.),
+            flags_alpha_cutoff: pack2x16float(0., alpha_cutoff) | flags,
         }
     }
 }
@@ -314,9 +555,64 @@ impl From<StandardMaterial> for SdMaterial {
             roughness: source.perceptual_roughness,
             fresnel: 0.,
             metallic: source.metallic,
-            sss_strength: 0.,
-            sss_radius: LinearRgba::ZERO.into(),
+            // Diffuse transmission goes through the subsurface path with the thickness as scattering distance
+            sss_strength: source.diffuse_transmission,
+            sss_radius: LinearRgba::rgb(source.thickness, source.thickness, source.thickness)
+                .into(),
+            emissive: source.emissive,
+            emissive_exposure_weight: source.emissive_exposure_weight,
+            reflectance: source.reflectance,
+            specular_tint: source.specular_tint,
+            specular_transmission: source.specular_transmission,
+            ior: source.ior,
+            attenuation_distance: source.attenuation_distance,
+            attenuation_color: source.attenuation_color,
+            unlit: source.unlit,
+            alpha_mode: source.alpha_mode,
+        }
+    }
+}
+
+// Materials of the sd_materials buffer, equal materials and StandardMaterial handles share one entry
+#[derive(Default)]
+pub struct SdMaterialTable {
+    materials: Vec<SdMaterialUniform>,
+    indices: HashMap<SdMaterialUniform, u32>,
+    handles: HashMap<AssetId<StandardMaterial>, u32>,
+    transmission: bool,
+}
+
+impl SdMaterialTable {
+    pub fn insert(&mut self, material: SdMaterial) -> u32 {
+        self.transmission |= material.specular_transmission > 0.;
+        let uniform = material.uniform();
+        *self.indices.entry(uniform).or_insert_with(|| {
+            self.materials.push(uniform);
+            self.materials.len() as u32 - 1
+        })
+    }
+
+    // The StandardMaterial is only converted the first time its handle is seen
+    pub fn insert_standard(
+        &mut self,
+        handle: AssetId<StandardMaterial>,
+        material: &StandardMaterial,
+    ) -> u32 {
+        if let Some(&index) = self.handles.get(&handle) {
+            return index;
         }
+        let index = self.insert(SdMaterial::from(material.clone()));
+        self.handles.insert(handle, index);
+        index
+    }
+
+    pub fn uniforms(&self) -> &[SdMaterialUniform] {
+        &self.materials
+    }
+
+    // Refraction is only compiled into the raymarcher when a material can use it
+    pub fn has_transmission(&self) -> bool {
+        self.transmission
     }
 }
 
@@ -337,3 +633,152 @@ impl SdTransform {
         unsafe { transmute(self) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shape_uniform_packs_limits() {
+        let uniform =
+            SdShapeUniform::new(SD_EMPTY_TYPE_ID - 1, u16::MAX as usize, u8::MAX as usize);
+        let [type_id, index_lo, index_hi, len] = uniform.type_id_index_len.to_ne_bytes();
+        assert_eq!(uniform.type_id(), (SD_EMPTY_TYPE_ID - 1) as u32);
+        assert_eq!(type_id, SD_EMPTY_TYPE_ID - 1);
+        assert_eq!(u16::from_ne_bytes([index_lo, index_hi]), u16::MAX);
+        assert_eq!(len, u8::MAX);
+    }
+
+    #[test]
+    fn empty_shape_uniform() {
+        assert_eq!(SdShapeUniform::empty().type_id(), SD_EMPTY_TYPE_ID as u32);
+    }
+
+    #[test]
+    fn shape_uniform_uses_field_count() {
+        let uniform = SdShape::RoundBox {
+            bounds: Vec3::ONE,
+            radius: 0.1,
+        }
+        .uniform(7);
+        let [_, index_lo, index_hi, len] = uniform.type_id_index_len.to_ne_bytes();
+        assert_eq!(u16::from_ne_bytes([index_lo, index_hi]), 7);
+        assert_eq!(len, 4);
+    }
+
+    #[test]
+    fn custom_mod_uniform_points_at_payload() {
+        let uniform = SdMod::Custom {
+            id: SdCustomModId(0x81),
+            data: vec![1., 2., 3.],
+        }
+        .uniform(42);
+        assert_eq!(uniform.type_id, 0x81);
+        assert_eq!(uniform.data_index, 42);
+        assert_eq!(uniform.data, Vec4::ZERO);
+    }
+
+    #[test]
+    fn mod_stack_uniform_packs_start_and_len() {
+        let stack = SdModStack {
+            modifiers: vec![SdMod::SymetryX, SdMod::SymetryY],
+        };
+        let uniform = stack.uniform(300);
+        assert_eq!(uniform.data_index_and_lenght >> 16, 300);
+        assert_eq!(uniform.data_index_and_lenght & 0xFFFF, 2);
+    }
+
+    // Inverse of `pack2x16float`, like WGSL `unpack2x16float`
+    fn unpack2x16float(packed: u32) -> (f32, f32) {
+        (
+            f16::from_bits(packed as u16).to_f32(),
+            f16::from_bits((packed >> 16) as u16).to_f32(),
+        )
+    }
+
+    #[test]
+    fn material_uniform_round_trips_f16() {
+        let uniform = SdMaterial {
+            color: Color::linear_rgba(0.25, 0.5, 0.75, 1.),
+            roughness: 0.3,
+            metallic: 1.,
+            ior: 1.5,
+            ..default()
+        }
+        .uniform();
+
+        let (r, g) = unpack2x16float(uniform.color_rg);
+        let (b, a) = unpack2x16float(uniform.color_ba);
+        assert_eq!((r, g, b, a), (0.25, 0.5, 0.75, 1.));
+        let (roughness, _) = unpack2x16float(uniform.roughness_fresnel);
+        assert!((roughness - 0.3).abs() < 1e-3);
+        let (metallic, _) = unpack2x16float(uniform.metallic_sss_strength);
+        assert_eq!(metallic, 1.);
+        let (_, ior) = unpack2x16float(uniform.transmission_ior);
+        assert_eq!(ior, 1.5);
+    }
+
+    #[test]
+    fn material_uniform_keeps_flags_beside_alpha_cutoff() {
+        let uniform = SdMaterial {
+            alpha_mode: AlphaMode::Mask(0.5),
+            unlit: true,
+            ..default()
+        }
+        .uniform();
+
+        let flags = uniform.flags_alpha_cutoff & 0xFFFF;
+        assert_eq!(flags & SD_MATERIAL_FLAGS_UNLIT, SD_MATERIAL_FLAGS_UNLIT);
+        assert_eq!(
+            flags >> SD_MATERIAL_FLAGS_ALPHA_MODE_SHIFT,
+            SD_MATERIAL_ALPHA_MODE_MASK
+        );
+        let (_, alpha_cutoff) = unpack2x16float(uniform.flags_alpha_cutoff);
+        assert_eq!(alpha_cutoff, 0.5);
+    }
+
+    fn standard_id(uuid: u128) -> AssetId<StandardMaterial> {
+        AssetId::Uuid {
+            uuid: bevy::asset::uuid::Uuid::from_u128(uuid),
+        }
+    }
+
+    #[test]
+    fn material_table_dedups_by_value() {
+        let mut table = SdMaterialTable::default();
+        let red = SdMaterial {
+            color: Color::linear_rgb(1., 0., 0.),
+            ..default()
+        };
+        assert_eq!(table.insert(SdMaterial::default()), 0);
+        assert_eq!(table.insert(red), 1);
+        assert_eq!(table.insert(SdMaterial::default()), 0);
+        assert_eq!(table.insert(red), 1);
+        assert_eq!(table.uniforms().len(), 2);
+    }
+
+    #[test]
+    fn material_table_dedups_by_handle() {
+        let mut table = SdMaterialTable::default();
+        let red = StandardMaterial::from_color(Color::linear_rgb(1., 0., 0.));
+        let first = table.insert_standard(standard_id(1), &red);
+        // A handle already seen is not converted again, even if the asset differs
+        assert_eq!(table.insert_standard(standard_id(1), &StandardMaterial::default()), first);
+        // Another handle with the same values shares the entry
+        assert_eq!(table.insert_standard(standard_id(2), &red), first);
+        assert_eq!(table.insert(SdMaterial::from(red)), first);
+        assert_eq!(table.uniforms().len(), 1);
+    }
+
+    #[test]
+    fn material_table_tracks_transmission() {
+        let mut table = SdMaterialTable::default();
+        table.insert(SdMaterial::default());
+        assert!(!table.has_transmission());
+        table.insert(SdMaterial {
+            specular_transmission: 0.9,
+            ..default()
+        });
+        assert!(table.has_transmission());
+    }
+}
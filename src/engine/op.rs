@@ -1,3 +1,4 @@
+use crate::engine::blend_registry::SdCustomBlendId;
 use crate::engine::hierarchy::SdOperatedBy;
 use bevy::ecs::lifecycle::HookContext;
 use bevy::ecs::world::DeferredWorld;
@@ -18,12 +19,13 @@ pub struct SdOperatorUniform {
 }
 
 impl SdOperator {
-    pub fn uniform(self) -> SdOperatorUniform {
+    // `data_index` is where the params of a Custom blend are in the field data
+    pub fn uniform(self, data_index: usize) -> SdOperatorUniform {
         // Pack lhs (lower 16 bits) and rhs (upper 16 bits) into u32
         let lhs_rhs = (self.lhs as u32) | ((self.rhs as u32) << 16);
 
         // Pack the operation into a u32
-        let op = self.op.uniform();
+        let op = self.op.uniform(data_index);
 
         SdOperatorUniform { op, lhs_rhs }
     }
@@ -31,9 +33,22 @@ impl SdOperator {
 
 #[derive(ShaderType, Clone, Copy)]
 pub struct SdBlendUniform {
-    pub type_id_data: u32,
+    // Type id and rev
+    pub type_id_rev: u32,
+    pub data: f32,
+    // Index of the params of a Custom blend in the field data, a full u32 like the other offsets
+    pub data_index: u32,
 }
 
+impl SdBlendUniform {
+    #[inline]
+    pub fn type_id(&self) -> u32 {
+        self.type_id_rev & 0xFF
+    }
+}
+
+// WARN: Blends mix the materials of their patients by weight but a sample holds at most two materials.
+// When smooth blends of three or more materials overlap only the two heaviest are kept and renormalized
 #[repr(u32)]
 #[derive(Reflect, Component, Debug, Clone, Copy, Default)]
 #[require(Name::new("SdOp"), SdIndex)]
@@ -69,28 +84,36 @@ pub enum SdBlend {
         rev: bool,
         strength: f32,
     },
+    // An operator of the SdBlendRegistry
+    Custom {
+        id: SdCustomBlendId,
+        rev: bool,
+        params: Vec4,
+    },
 }
 
 impl SdBlend {
-    pub fn uniform(self) -> SdBlendUniform {
+    pub fn uniform(self, data_index: usize) -> SdBlendUniform {
         use SdBlend::*;
-        let (disc, rev, extra): (u8, u8, u16) = match self {
-            Union => (0, 0, 0),
-            Subtract { rev } => (1, rev as u8, 0),
-            Intersect => (2, 0, 0),
-            ChamferUnion { radius } => (3, 0, (radius * 255.0) as u16), // approximate
-            ChamferSubtract { rev, radius } => (4, rev as u8, (radius * 255.0) as u16),
-            ChamferIntersect { radius } => (5, 0, (radius * 255.0) as u16),
-            SmoothUnion { k } => (6, 0, (k * 255.0) as u16),
-            SmoothSubtract { rev, k } => (7, rev as u8, (k * 255.0) as u16),
-            SmoothIntersect { k } => (8, 0, (k * 255.0) as u16),
-            Displace { rev, strength } => (9, rev as u8, (strength * 255.0) as u16),
+        let (disc, rev, data): (u8, bool, f32) = match self {
+            Union => (0, false, 0.),
+            Subtract { rev } => (1, rev, 0.),
+            Intersect => (2, false, 0.),
+            ChamferUnion { radius } => (3, false, radius),
+            ChamferSubtract { rev, radius } => (4, rev, radius),
+            ChamferIntersect { radius } => (5, false, radius),
+            SmoothUnion { k } => (6, false, k),
+            SmoothSubtract { rev, k } => (7, rev, k),
+            SmoothIntersect { k } => (8, false, k),
+            Displace { rev, strength } => (9, rev, strength),
+            // Custom params do not fit here, they are read from the field data
+            Custom { id, rev, .. } => (id.0, rev, 0.),
         };
 
-        let id_data = (disc as u32) | ((rev as u32) << 8) | ((extra as u32) << 16);
-
         SdBlendUniform {
-            type_id_data: id_data,
+            type_id_rev: (disc as u32) | ((rev as u32) << 8),
+            data,
+            data_index: data_index as u32,
         }
     }
 }
@@ -118,3 +141,60 @@ fn set_index(world: &mut DeferredWorld, entity: Entity, index: u32) {
         sd_index.0 = index;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_blend_uniform_uses_registry_id() {
+        let uniform = SdBlend::Custom {
+            id: SdCustomBlendId(0xFF),
+            rev: false,
+            params: Vec4::ONE,
+        }
+        .uniform(12);
+        assert_eq!(uniform.type_id(), 0xFF);
+        assert_eq!(uniform.type_id_rev >> 8, 0);
+        assert_eq!(uniform.data_index, 12);
+        // The params are read from the field data
+        assert_eq!(uniform.data, 0.);
+    }
+
+    #[test]
+    fn blend_uniform_packs_type_rev_and_data() {
+        let uniform = SdBlend::SmoothSubtract { rev: true, k: 0.25 }.uniform(0);
+        assert_eq!(uniform.type_id(), 7);
+        assert_eq!(uniform.type_id_rev >> 8, 1);
+        assert_eq!(uniform.data, 0.25);
+
+        let uniform = SdBlend::Union.uniform(0);
+        assert_eq!(uniform.type_id_rev, 0);
+    }
+
+    #[test]
+    fn custom_blend_data_index_is_not_truncated() {
+        let data_index = u16::MAX as usize + 1;
+        let uniform = SdBlend::Custom {
+            id: SdCustomBlendId(0x80),
+            rev: true,
+            params: Vec4::ONE,
+        }
+        .uniform(data_index);
+        assert_eq!(uniform.data_index, data_index as u32);
+        assert_eq!(uniform.type_id_rev, 0x180);
+    }
+
+    #[test]
+    fn operator_uniform_packs_patients() {
+        let uniform = SdOperator {
+            op: SdBlend::Intersect,
+            lhs: 3,
+            rhs: u16::MAX,
+        }
+        .uniform(0);
+        assert_eq!(uniform.lhs_rhs & 0xFFFF, 3);
+        assert_eq!(uniform.lhs_rhs >> 16, u16::MAX as u32);
+        assert_eq!(uniform.op.type_id(), 2);
+    }
+}
@@ -1,23 +1,38 @@
 use std::borrow::Cow;
 
 use bevy::{
+    core_pipeline::{FullscreenShader, core_3d::CORE_3D_DEPTH_FORMAT},
+    light::ShadowFilteringMethod,
+    platform::collections::HashMap,
     pbr::{GpuClusterableObjectsStorage, GpuLights},
     prelude::*,
     render::{
+        globals::GlobalsUniform,
+        render_asset::RenderAssets,
         render_resource::{
             BindGroupLayoutDescriptor, BindGroupLayoutEntries, CachedComputePipelineId,
-            ComputePipelineDescriptor, PipelineCache, ShaderStages, StorageTextureAccess,
-            TextureFormat,
+            CachedRenderPipelineId, CompareFunction, ComputePipelineDescriptor, DepthBiasState,
+            DepthStencilState, FragmentState, PipelineCache, RenderPipelineDescriptor,
+            SamplerBindingType, ShaderStages, StencilState, StorageTextureAccess, TextureFormat,
+            TextureSampleType,
             binding_types::{
-                storage_buffer_read_only, storage_buffer_read_only_sized, texture_depth_2d,
+                sampler, storage_buffer_read_only, storage_buffer_read_only_sized, texture_2d,
+                texture_2d_array, texture_cube, texture_cube_array, texture_depth_2d,
                 texture_storage_2d, uniform_buffer,
             },
         },
+        texture::GpuImage,
         view::ViewUniform,
     },
+    shader::ShaderDefVal,
 };
 
-use super::{RAY_MARCH_COMPUTE_PASS_HANDLE, RayMarchCamera};
+use super::{
+    RAY_MARCH_COMPUTE_PASS_HANDLE,
+    buffer::RayMarchBuffer,
+    camera::{RayMarchCamera, RayMarchCameraUniform, RayMarchEnvironmentMap, SdShadowMode},
+    compile::MAX_COMPILED_MAPS,
+};
 
 #[derive(Resource)]
 pub struct RayMarchEnginePipeline {
@@ -25,8 +40,127 @@ pub struct RayMarchEnginePipeline {
     pub texture_layout: BindGroupLayoutDescriptor,
     pub storage_layout: BindGroupLayoutDescriptor,
     pub prepass_layout: BindGroupLayoutDescriptor,
-    pub compute_raymarch_pipeline: CachedComputePipelineId,
+    pub shadow_view_layout: BindGroupLayoutDescriptor,
     pub compute_mask_pipeline: CachedComputePipelineId,
+    pub raymarch_pipelines: HashMap<RayMarchPipelineKey, CachedComputePipelineId>,
+    // Pipelines writing the SDFs into shadow maps, by compiled map hash and max prefab ops
+    pub shadow_caster_pipelines: HashMap<(Option<u64>, u32), CachedRenderPipelineId>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RayMarchPipelineKey {
+    pub shadow_mode: SdShadowMode,
+    // SHADOW_FILTER_METHOD_* of the view, Bevy's shadow maps are sampled like on meshes
+    pub shadow_filter: &'static str,
+    // Lit by the EnvironmentMapLight of the view
+    pub environment_map: bool,
+    pub reflections: bool,
+    // Only when a material of the scene has specular transmission
+    pub transmission: bool,
+    // Only when the scene has SdVolumes
+    pub volumes: bool,
+    // Sizes the results of instance and volume programs
+    pub max_prefab_ops: u32,
+    // Topology hash of the compiled map the pipeline is specialized for
+    pub compiled_map: Option<u64>,
+}
+
+impl RayMarchPipelineKey {
+    pub fn new(
+        camera: &RayMarchCamera,
+        shadow_filter: Option<&ShadowFilteringMethod>,
+        environment_map: bool,
+        volumes: bool,
+        transmission: bool,
+        max_prefab_ops: u32,
+        compiled_map: Option<u64>,
+    ) -> Self {
+        let shadow_filter = match shadow_filter.copied().unwrap_or_default() {
+            ShadowFilteringMethod::Hardware2x2 => "SHADOW_FILTER_METHOD_HARDWARE_2X2",
+            ShadowFilteringMethod::Gaussian => "SHADOW_FILTER_METHOD_GAUSSIAN",
+            ShadowFilteringMethod::Temporal => "SHADOW_FILTER_METHOD_TEMPORAL",
+        };
+        Self {
+            shadow_mode: camera.shadow_mode,
+            shadow_filter,
+            environment_map,
+            reflections: camera.reflection_bounces > 0,
+            transmission: transmission && camera.transmission_steps > 0,
+            volumes: volumes && camera.volume_max_steps > 0,
+            max_prefab_ops,
+            compiled_map,
+        }
+    }
+
+    fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut shader_defs = vec![
+            self.shadow_filter.into(),
+            max_prefab_ops_def(self.max_prefab_ops),
+        ];
+        shader_defs.extend(self.shadow_mode.shader_def());
+        if self.environment_map {
+            shader_defs.push("SD_ENVIRONMENT_MAP".into());
+        }
+        if self.reflections {
+            shader_defs.push("SD_REFLECTIONS".into());
+        }
+        if self.transmission {
+            shader_defs.push("SD_TRANSMISSION".into());
+        }
+        if self.volumes {
+            shader_defs.push("SD_VOLUMES".into());
+        }
+        shader_defs
+    }
+}
+
+fn max_prefab_ops_def(max_prefab_ops: u32) -> ShaderDefVal {
+    ShaderDefVal::UInt("SD_MAX_PREFAB_OPS".into(), max_prefab_ops)
+}
+
+impl RayMarchEnginePipeline {
+    fn layout(&self) -> Vec<BindGroupLayoutDescriptor> {
+        vec![
+            self.common_layout.clone(),
+            self.texture_layout.clone(),
+            self.storage_layout.clone(),
+            self.prepass_layout.clone(),
+        ]
+    }
+
+    fn queue_shadow_caster_pipeline(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        fullscreen_shader: &FullscreenShader,
+        shader: Handle<Shader>,
+        key: (Option<u64>, u32),
+    ) {
+        let pipeline = pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
+            label: Some("raymarch_shadow_caster_pipeline".into()),
+            layout: vec![
+                self.shadow_view_layout.clone(),
+                self.texture_layout.clone(),
+                self.storage_layout.clone(),
+            ],
+            vertex: fullscreen_shader.to_vertex_state(),
+            fragment: Some(FragmentState {
+                shader,
+                shader_defs: vec![max_prefab_ops_def(key.1)],
+                entry_point: Some(Cow::from("shadow_caster")),
+                targets: vec![],
+            }),
+            // Same depth state as the shadow pass of bevy_pbr
+            depth_stencil: Some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            ..default()
+        });
+        self.shadow_caster_pipelines.insert(key, pipeline);
+    }
 }
 
 pub(crate) fn init_raymarch_compute_pipeline(
@@ -40,31 +174,47 @@ pub(crate) fn init_raymarch_compute_pipeline(
             (
                 (0, uniform_buffer::<ViewUniform>(true)),
                 (1, uniform_buffer::<GpuLights>(true)),
+                // Shadow maps of the view, meshes shadow the SDFs through them
+                (2, texture_cube_array(TextureSampleType::Depth)),
+                (3, sampler(SamplerBindingType::Comparison)),
+                (5, texture_2d_array(TextureSampleType::Depth)),
+                (6, sampler(SamplerBindingType::Comparison)),
                 (
                     8,
                     storage_buffer_read_only::<GpuClusterableObjectsStorage>(false),
                 ),
+                (11, uniform_buffer::<GlobalsUniform>(false)),
             ),
         ),
     );
 
+    // The shadow caster pass raymarches in a fragment shader
     let texture_layout = BindGroupLayoutDescriptor::new(
         "raymarch_texture_bind_group_layout",
         &BindGroupLayoutEntries::sequential(
-            ShaderStages::COMPUTE,
-            (texture_depth_2d(), uniform_buffer::<RayMarchCamera>(true)),
+            ShaderStages::COMPUTE | ShaderStages::FRAGMENT,
+            (
+                texture_depth_2d(),
+                uniform_buffer::<RayMarchCameraUniform>(true),
+                texture_cube(TextureSampleType::Float { filterable: true }),
+                texture_cube(TextureSampleType::Float { filterable: true }),
+                sampler(SamplerBindingType::Filtering),
+            ),
         ),
     );
 
     let storage_layout = BindGroupLayoutDescriptor::new(
         "raymarch_storage_bind_group_layout",
         &BindGroupLayoutEntries::sequential(
-            ShaderStages::COMPUTE,
+            ShaderStages::COMPUTE | ShaderStages::FRAGMENT,
             (
                 storage_buffer_read_only_sized(false, None),
                 storage_buffer_read_only_sized(false, None),
                 storage_buffer_read_only_sized(false, None),
                 storage_buffer_read_only_sized(false, None),
+                storage_buffer_read_only_sized(false, None),
+                storage_buffer_read_only_sized(false, None),
+                storage_buffer_read_only_sized(false, None),
             ),
         ),
     );
@@ -78,22 +228,20 @@ pub(crate) fn init_raymarch_compute_pipeline(
                 texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::WriteOnly),
                 texture_storage_2d(TextureFormat::R16Float, StorageTextureAccess::WriteOnly),
                 texture_storage_2d(TextureFormat::Rgba16Float, StorageTextureAccess::ReadWrite),
+                // Main texture of the view with the opaque meshes, seen through transmissive SDFs
+                texture_2d(TextureSampleType::Float { filterable: false }),
             ),
         ),
     );
 
-    let march_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-        label: Some("raymarch_pipeline_compute_raymarch_pass".into()),
-        layout: vec![
-            common_layout.clone(),
-            texture_layout.clone(),
-            storage_layout.clone(),
-            prepass_layout.clone(),
-        ],
-        shader: RAY_MARCH_COMPUTE_PASS_HANDLE,
-        entry_point: Some(Cow::from("compute_raymarch")),
-        ..default()
-    });
+    // Only the view of the light, its shadow maps are the render target
+    let shadow_view_layout = BindGroupLayoutDescriptor::new(
+        "raymarch_shadow_view_bind_group_layout",
+        &BindGroupLayoutEntries::single(
+            ShaderStages::VERTEX_FRAGMENT,
+            uniform_buffer::<ViewUniform>(true),
+        ),
+    );
 
     let scale_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
         label: Some("raymarch_pipeline_compute_mask_pass".into()),
@@ -113,7 +261,125 @@ pub(crate) fn init_raymarch_compute_pipeline(
         texture_layout,
         storage_layout,
         prepass_layout,
-        compute_raymarch_pipeline: march_pipeline,
+        shadow_view_layout,
         compute_mask_pipeline: scale_pipeline,
+        raymarch_pipelines: HashMap::default(),
+        shadow_caster_pipelines: HashMap::default(),
     });
 }
+
+// Queues the raymarch pipelines the cameras need, the interpreted one for their shadow settings
+// and the one specialized for the current compiled map
+pub(crate) fn queue_raymarch_pipelines(
+    mut ray_march_pipeline: ResMut<RayMarchEnginePipeline>,
+    raymarch_buffer: Res<RayMarchBuffer>,
+    pipeline_cache: Res<PipelineCache>,
+    fullscreen_shader: Res<FullscreenShader>,
+    images: Res<RenderAssets<GpuImage>>,
+    cameras: Query<(
+        &RayMarchCamera,
+        Option<&ShadowFilteringMethod>,
+        &RayMarchEnvironmentMap,
+    )>,
+) {
+    let compiled_map = raymarch_buffer.compiled_map.as_ref();
+    let max_prefab_ops = raymarch_buffer.max_prefab_ops;
+
+    if cameras.iter().any(|(camera, ..)| camera.cast_shadow_maps) {
+        let compiled_key = (compiled_map.map(|map| map.hash), max_prefab_ops);
+        if !ray_march_pipeline
+            .shadow_caster_pipelines
+            .contains_key(&(None, max_prefab_ops))
+        {
+            ray_march_pipeline.queue_shadow_caster_pipeline(
+                &pipeline_cache,
+                &fullscreen_shader,
+                RAY_MARCH_COMPUTE_PASS_HANDLE,
+                (None, max_prefab_ops),
+            );
+        }
+        if let Some(map) = compiled_map
+            && !ray_march_pipeline
+                .shadow_caster_pipelines
+                .contains_key(&compiled_key)
+        {
+            let compiled_count = ray_march_pipeline
+                .shadow_caster_pipelines
+                .keys()
+                .filter(|(hash, _)| hash.is_some())
+                .count();
+            if compiled_count >= MAX_COMPILED_MAPS {
+                ray_march_pipeline
+                    .shadow_caster_pipelines
+                    .retain(|(hash, _), _| hash.is_none());
+            }
+            ray_march_pipeline.queue_shadow_caster_pipeline(
+                &pipeline_cache,
+                &fullscreen_shader,
+                map.shader.clone(),
+                compiled_key,
+            );
+        }
+    }
+
+    for (camera, shadow_filter, environment_map) in &cameras {
+        let environment_map = environment_map.gpu_images(&images).is_some();
+        let key = RayMarchPipelineKey::new(
+            camera,
+            shadow_filter,
+            environment_map,
+            raymarch_buffer.volumes,
+            raymarch_buffer.transmission,
+            max_prefab_ops,
+            None,
+        );
+        if !ray_march_pipeline.raymarch_pipelines.contains_key(&key) {
+            let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("raymarch_pipeline_compute_raymarch_pass".into()),
+                layout: ray_march_pipeline.layout(),
+                shader: RAY_MARCH_COMPUTE_PASS_HANDLE,
+                shader_defs: key.shader_defs(),
+                entry_point: Some(Cow::from("compute_raymarch")),
+                ..default()
+            });
+            ray_march_pipeline.raymarch_pipelines.insert(key, pipeline);
+        }
+
+        let Some(compiled_map) = compiled_map else {
+            continue;
+        };
+        let key = RayMarchPipelineKey::new(
+            camera,
+            shadow_filter,
+            environment_map,
+            raymarch_buffer.volumes,
+            raymarch_buffer.transmission,
+            max_prefab_ops,
+            Some(compiled_map.hash),
+        );
+        if ray_march_pipeline.raymarch_pipelines.contains_key(&key) {
+            continue;
+        }
+
+        let compiled_count = ray_march_pipeline
+            .raymarch_pipelines
+            .keys()
+            .filter(|key| key.compiled_map.is_some())
+            .count();
+        if compiled_count >= MAX_COMPILED_MAPS {
+            ray_march_pipeline
+                .raymarch_pipelines
+                .retain(|key, _| key.compiled_map.is_none());
+        }
+
+        let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("raymarch_pipeline_compute_compiled_raymarch_pass".into()),
+            layout: ray_march_pipeline.layout(),
+            shader: compiled_map.shader.clone(),
+            shader_defs: key.shader_defs(),
+            entry_point: Some(Cow::from("compute_raymarch")),
+            ..default()
+        });
+        ray_march_pipeline.raymarch_pipelines.insert(key, pipeline);
+    }
+}
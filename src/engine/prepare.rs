@@ -1,32 +1,43 @@
 use bevy::{
     core_pipeline::prepass::ViewPrepassTextures,
-    pbr::{GlobalClusterableObjectMeta, LightMeta},
+    platform::collections::{HashMap, HashSet},
+    pbr::{GlobalClusterableObjectMeta, LightMeta, ShadowSamplers, ViewShadowBindings},
     prelude::*,
     render::{
         camera::ExtractedCamera,
         extract_component::ComponentUniforms,
+        globals::GlobalsBuffer,
+        render_asset::RenderAssets,
         render_resource::{
             BindGroupEntries, BufferUsages, BufferVec, Extent3d, PipelineCache, TextureAspect,
             TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
             TextureViewDescriptor,
         },
         renderer::{RenderDevice, RenderQueue},
+        texture::{FallbackImage, GpuImage},
         view::ViewUniforms,
     },
 };
 
 use crate::engine::{
-    SdIndex,
-    buffer::RayMarchBuffer,
-    camera::RayMarchCamera,
+    blend_registry::SdBlendRegistry,
+    buffer::{RayMarchBuffer, SD_MIN_PREFAB_OPS},
+    compile::{SdCompiledLeaf, SdCompiledMaps, SdMapMode, compiled_map_wgsl},
+    camera::{RayMarchCamera, RayMarchCameraUniform, RayMarchEnvironmentMap},
     hierarchy::{SdOperatedBy, SdOperatingOn},
+    instance::{SdInstance, SdPrefab},
+    mod_registry::SdModRegistry,
     nodes::RayMarchEngineBindGroup,
     object::{
-        SdMaterial, SdModStack, SdModUniform, SdObject, SdObjectUniform, SdShape, SdTransform,
+        SdMaterial, SdMaterialTable, SdMaterialUniform, SdMod, SdModStack, SdModUniform,
+        SdObjectUniform, SdShape, SdShapeUniform, SdTransform,
     },
     op::{SdBlend, SdOperator, SdOperatorUniform},
     pipeline::RayMarchEnginePipeline,
     prepass::RayMarchPrepass,
+    shape_registry::{SdCustomShape, SdShapeRegistry},
+    units::SdUnits,
+    volume::{SdVolume, SdVolumeUniform},
 };
 
 pub(crate) fn prepare_raymarch_textures(
@@ -105,7 +116,7 @@ pub(crate) fn prepare_raymarch_textures(
         let mask = render_device
             .create_texture(&TextureDescriptor {
                 label: Some("raymarch_mask_texture"),
-                size: size,
+                size,
                 mip_level_count: 1,
                 sample_count: 1,
                 dimension: TextureDimension::D2,
@@ -154,38 +165,66 @@ pub(crate) fn prepare_raymarch_textures(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn prepare_raymarch_bind_group(
     mut commands: Commands,
     device: Res<RenderDevice>,
-    query: Query<(&ViewPrepassTextures, &RayMarchPrepass), With<RayMarchCamera>>,
+    query: Query<
+        (
+            &ViewPrepassTextures,
+            &ViewShadowBindings,
+            &RayMarchEnvironmentMap,
+        ),
+        With<RayMarchCamera>,
+    >,
     ray_march_pipeline: Res<RayMarchEnginePipeline>,
     raymarch_buffer: Option<Res<RayMarchBuffer>>,
     pipeline_cache: Res<PipelineCache>,
-    settings_uniforms: Res<ComponentUniforms<RayMarchCamera>>,
+    settings_uniforms: Res<ComponentUniforms<RayMarchCameraUniform>>,
     view_uniforms: Res<ViewUniforms>,
     light_meta: Res<LightMeta>,
     clusterables: Res<GlobalClusterableObjectMeta>,
+    shadow_samplers: Res<ShadowSamplers>,
+    globals_buffer: Res<GlobalsBuffer>,
+    images: Res<RenderAssets<GpuImage>>,
+    fallback_image: Res<FallbackImage>,
 ) {
-    let Ok((view_prepass, raymarch_prepass)) = query.single() else {
+    let Ok((view_prepass, shadow_bindings, environment_map)) = query.single()
+    else {
         return;
     };
-    let (Some(settings_binding), Some(view_binding), Some(light_binding), Some(cluster_binding)) = (
+    let (
+        Some(settings_binding),
+        Some(view_binding),
+        Some(light_binding),
+        Some(cluster_binding),
+        Some(globals_binding),
+    ) = (
         settings_uniforms.uniforms().binding(),
         view_uniforms.uniforms.binding(),
         light_meta.view_gpu_lights.binding(),
         clusterables.gpu_clusterable_objects.binding(),
+        globals_buffer.buffer.binding(),
     ) else {
         return;
     };
 
     let march_buffer = unsafe { raymarch_buffer.unwrap_unchecked() };
 
+    // The pipeline only samples the fallbacks without SD_ENVIRONMENT_MAP
+    let (diffuse_map, specular_map) = environment_map
+        .gpu_images(&images)
+        .unwrap_or((&fallback_image.cube, &fallback_image.cube));
+
     let texture_bind_group = device.create_bind_group(
         "ray_march_texture_bind_group",
         &pipeline_cache.get_bind_group_layout(&ray_march_pipeline.texture_layout),
         &BindGroupEntries::sequential((
             view_prepass.depth_view().unwrap(),
             settings_binding.clone(),
+            &diffuse_map.texture_view,
+            &specular_map.texture_view,
+            &specular_map.sampler,
         )),
     );
 
@@ -195,7 +234,12 @@ pub(crate) fn prepare_raymarch_bind_group(
         &BindGroupEntries::with_indices((
             (0, view_binding.clone()),
             (1, light_binding.clone()),
+            (2, &shadow_bindings.point_light_depth_texture_view),
+            (3, &shadow_samplers.point_light_comparison_sampler),
+            (5, &shadow_bindings.directional_light_depth_texture_view),
+            (6, &shadow_samplers.directional_light_comparison_sampler),
             (8, cluster_binding.clone()),
+            (11, globals_binding.clone()),
         )),
     );
 
@@ -207,17 +251,9 @@ pub(crate) fn prepare_raymarch_bind_group(
             march_buffer.operator.as_entire_buffer_binding(),
             march_buffer.modifier.as_entire_buffer_binding(),
             march_buffer.field_data.as_entire_buffer_binding(),
-        )),
-    );
-
-    let prepass_bind_group = device.create_bind_group(
-        "marcher_prepass_bind_group",
-        &pipeline_cache.get_bind_group_layout(&ray_march_pipeline.prepass_layout),
-        &BindGroupEntries::sequential((
-            &raymarch_prepass.depth,
-            &raymarch_prepass.normal,
-            &raymarch_prepass.mask,
-            &raymarch_prepass.output,
+            march_buffer.prefab_operator.as_entire_buffer_binding(),
+            march_buffer.material.as_entire_buffer_binding(),
+            march_buffer.volume.as_entire_buffer_binding(),
         )),
     );
 
@@ -225,59 +261,332 @@ pub(crate) fn prepare_raymarch_bind_group(
         common_bind_group,
         texture_bind_group,
         storage_bind_group,
-        prepass_bind_group,
     });
 }
 
+// A patient of an SdBlend once the tree is flattened
+#[derive(Clone, Copy)]
+enum SdPatient {
+    Object(u16),
+    Op(u16),
+}
+
+// Flattens the tree under `entity` in post-order so every op comes after its patients.
+// An SdVolume subtree is a single leaf, its own program is flattened with `flatten_sd_node`
+fn flatten_sd_tree(
+    entity: Entity,
+    units: SdUnits,
+    sd_op_query: &Query<(&SdBlend, &SdOperatingOn)>,
+    sd_volume_query: &Query<&SdVolume>,
+    leaves: &mut Vec<(Entity, SdUnits)>,
+    ops: &mut Vec<(SdBlend, SdPatient, SdPatient)>,
+    volumes: &mut HashSet<usize>,
+) -> SdPatient {
+    if sd_volume_query.contains(entity) {
+        volumes.insert(leaves.len());
+        leaves.push((entity, units));
+        return SdPatient::Object((leaves.len() - 1) as u16);
+    }
+    flatten_sd_node(entity, units, sd_op_query, sd_volume_query, leaves, ops, volumes)
+}
+
+fn flatten_sd_node(
+    entity: Entity,
+    units: SdUnits,
+    sd_op_query: &Query<(&SdBlend, &SdOperatingOn)>,
+    sd_volume_query: &Query<&SdVolume>,
+    leaves: &mut Vec<(Entity, SdUnits)>,
+    ops: &mut Vec<(SdBlend, SdPatient, SdPatient)>,
+    volumes: &mut HashSet<usize>,
+) -> SdPatient {
+    let Ok((&op, op_on)) = sd_op_query.get(entity) else {
+        leaves.push((entity, units));
+        return SdPatient::Object((leaves.len() - 1) as u16);
+    };
+
+    let args = op_on.clone().get_sd_argunments();
+    let lhs = flatten_sd_tree(args.1, units, sd_op_query, sd_volume_query, leaves, ops, volumes);
+    let rhs = flatten_sd_tree(args.0, units, sd_op_query, sd_volume_query, leaves, ops, volumes);
+
+    ops.push((op, lhs, rhs));
+    SdPatient::Op((ops.len() - 1) as u16)
+}
+
+// Appends an instance or volume program to `prefab_ops` and returns its range.
+// WARN: SdShapeUniform packs the range as a u16 start and a u8 length, a program past that is left empty
+fn push_prefab_program(
+    entity: Entity,
+    program: Vec<(SdBlend, SdPatient, SdPatient)>,
+    prefab_ops: &mut Vec<(SdBlend, SdPatient, SdPatient)>,
+) -> (usize, usize) {
+    if program.len() > u8::MAX as usize || prefab_ops.len() > u16::MAX as usize {
+        warn!(
+            "The program of {:?} has {} ops at offset {}, instances and volumes are limited to {} ops \
+             starting before {}. It is not rendered",
+            entity,
+            program.len(),
+            prefab_ops.len(),
+            u8::MAX,
+            u16::MAX
+        );
+        return (0, 0);
+    }
+    let range = (prefab_ops.len(), program.len());
+    prefab_ops.extend(program);
+    range
+}
+
+#[allow(clippy::type_complexity, clippy::too_many_arguments)]
 pub(crate) fn prepare_raymarch_buffer(
     mut commands: Commands,
     device: Res<RenderDevice>,
     queue: Res<RenderQueue>,
     sdf_object_query: Query<
         (
-            &SdShape,
+            AnyOf<(&SdShape, &SdCustomShape)>,
             &SdModStack,
             &GlobalTransform,
             Option<&MeshMaterial3d<StandardMaterial>>,
             Option<&SdMaterial>,
         ),
-        With<SdOperatedBy>,
+        Or<(With<SdOperatedBy>, With<SdVolume>)>,
+    >,
+    sd_instance_query: Query<&SdInstance, With<SdOperatedBy>>,
+    sd_op_query: Query<(&SdBlend, &SdOperatingOn)>,
+    sd_volume_query: Query<&SdVolume>,
+    sd_root_query: Query<
+        Entity,
+        (
+            Or<(With<SdBlend>, With<SdVolume>)>,
+            Without<SdOperatedBy>,
+            Without<SdPrefab>,
+        ),
     >,
-    sd_op_query: Query<(&SdBlend, &SdIndex, &SdOperatingOn)>,
+    sd_units_query: Query<&SdUnits>,
+    default_units: Res<SdUnits>,
+    (shape_registry, mod_registry, blend_registry): (
+        Res<SdShapeRegistry>,
+        Res<SdModRegistry>,
+        Res<SdBlendRegistry>,
+    ),
+    map_mode: Res<SdMapMode>,
+    mut compiled_maps: ResMut<SdCompiledMaps>,
+    mut shaders: ResMut<Assets<Shader>>,
     material_as: Res<Assets<StandardMaterial>>,
 ) {
-    let nb_shapes = sdf_object_query.iter().len() as u16;
+    let tree_units = |root: Entity| sd_units_query.get(root).copied().unwrap_or(*default_units);
+
+    let mut leaves = Vec::new();
+    let mut ops = Vec::new();
+    let mut prefab_ops = Vec::new();
+    // prefab entity -> range of its program in prefab_ops
+    let mut prefab_ranges = HashMap::<Entity, (usize, usize)>::default();
+    // Leaf index of every volume, their programs also go in prefab_ops
+    let mut volumes = HashSet::new();
+    let mut volume_ranges = HashMap::<usize, (usize, usize)>::default();
+
+    for root in sd_root_query.iter().sort::<Entity>() {
+        flatten_sd_tree(
+            root,
+            tree_units(root),
+            &sd_op_query,
+            &sd_volume_query,
+            &mut leaves,
+            &mut ops,
+            &mut volumes,
+        );
+    }
+
+    // NOTE: Volume roots are lone leaves that are not surfaces so they are left out of `map()`.
+    // When they are the only roots this op over the first of them keeps sd_ops from being empty,
+    // it evaluates to empty space like every volume leaf
+    if ops.is_empty() && !leaves.is_empty() {
+        ops.push((SdBlend::Union, SdPatient::Object(0), SdPatient::Object(0)));
+    }
+
+    // Every prefab is flattened once no matter how many instances reference it
+    let mut i = 0;
+    while i < leaves.len() {
+        if volumes.contains(&i) {
+            let (entity, units) = leaves[i];
+            let mut program = Vec::new();
+            let root = flatten_sd_node(
+                entity,
+                units,
+                &sd_op_query,
+                &sd_volume_query,
+                &mut leaves,
+                &mut program,
+                &mut volumes,
+            );
+            // The density of a volume over a lone shape still needs a program with one op
+            if let SdPatient::Object(_) = root {
+                program.push((SdBlend::Union, root, root));
+            }
+            volume_ranges.insert(i, push_prefab_program(entity, program, &mut prefab_ops));
+        } else if let Ok(instance) = sd_instance_query.get(leaves[i].0)
+            && !prefab_ranges.contains_key(&instance.prefab)
+        {
+            let mut program = Vec::new();
+            flatten_sd_tree(
+                instance.prefab,
+                tree_units(instance.prefab),
+                &sd_op_query,
+                &sd_volume_query,
+                &mut leaves,
+                &mut program,
+                &mut volumes,
+            );
+            prefab_ranges.insert(
+                instance.prefab,
+                push_prefab_program(instance.prefab, program, &mut prefab_ops),
+            );
+        }
+        i += 1;
+    }
+
+    // Sizes `prefab_results` in the shader, a power of two keeps the pipelines from being requeued on every op
+    let max_prefab_ops = prefab_ranges
+        .values()
+        .chain(volume_ranges.values())
+        .map(|&(_, op_len)| op_len as u32)
+        .max()
+        .unwrap_or_default()
+        .next_power_of_two()
+        .max(SD_MIN_PREFAB_OPS);
+
+    let nb_shapes = leaves.len() as u16;
 
-    let mut current_shape_index = 0;
-    let mut current_op_index = 0;
     let mut current_mod_index = 0;
     let mut current_field_data_index = 0;
 
     let mut sd_object_buffer = BufferVec::<SdObjectUniform>::new(BufferUsages::STORAGE);
     let mut sd_op_buffer = BufferVec::<SdOperatorUniform>::new(BufferUsages::STORAGE);
+    let mut sd_prefab_op_buffer = BufferVec::<SdOperatorUniform>::new(BufferUsages::STORAGE);
     let mut sd_mod_buffer = BufferVec::<SdModUniform>::new(BufferUsages::STORAGE);
     let mut sd_field_data_buffer = BufferVec::<f32>::new(BufferUsages::STORAGE);
+    let mut sd_material_buffer = BufferVec::<SdMaterialUniform>::new(BufferUsages::STORAGE);
+    let mut sd_volume_buffer = BufferVec::<SdVolumeUniform>::new(BufferUsages::STORAGE);
+
+    // Instances, volumes and empty leaves point to the default material at index 0
+    let mut materials = SdMaterialTable::default();
+    let default_material_index = materials.insert(SdMaterial::default());
+
+    // Keeps the object indices aligned for leaves that can't be rendered, it evaluates to nothing
+    let empty_uniform = SdObjectUniform {
+        shape: SdShapeUniform::empty(),
+        material_index: default_material_index,
+        modifier_stack: SdModStack::default().uniform(0),
+        transform: SdTransform {
+            pos: Vec3::ZERO,
+            rot: Vec3::ZERO,
+        }
+        .uniform(),
+    };
 
-    let mut push_object = |entity: Entity| -> Option<u16> {
-        let (&shape, modifier_stack, transform, some_mat_handle, some_sd_mat) =
-            sdf_object_query.get(entity).ok()?;
+    let instance_uniform = |instance: &SdInstance| -> SdObjectUniform {
+        let (op_start, op_len) = prefab_ranges
+            .get(&instance.prefab)
+            .copied()
+            .unwrap_or_default();
+
+        if op_len == 0 && !sd_op_query.contains(instance.prefab) {
+            warn!(
+                "SdInstance prefab {:?} is not an SdBlend tree",
+                instance.prefab
+            );
+        }
+
+        SdObjectUniform {
+            shape: SdShapeUniform::instance(op_start, op_len),
+            material_index: default_material_index,
+            modifier_stack: SdModStack::default().uniform(0),
+            transform: SdTransform {
+                pos: instance.transform.translation,
+                rot: Vec3::from(instance.transform.rotation.to_euler(EulerRot::XYZ)),
+            }
+            .uniform(),
+        }
+    };
+
+    // Leaves that are not plain shapes stay on the interpreter functions in a compiled map
+    let mut compiled_leaves = Vec::with_capacity(leaves.len());
+    compiled_leaves.resize_with(leaves.len(), || SdCompiledLeaf::Object);
+
+    for (leaf_index, &(entity, units)) in leaves.iter().enumerate() {
+        // The volume evaluates to empty space, its program is only read as density
+        if let (Some(&(op_start, op_len)), Ok(volume)) =
+            (volume_ranges.get(&leaf_index), sd_volume_query.get(entity))
+        {
+            sd_volume_buffer.push(volume.uniform(leaf_index));
+            sd_object_buffer.push(SdObjectUniform {
+                shape: SdShapeUniform::volume(op_start, op_len),
+                ..empty_uniform
+            });
+            continue;
+        }
+
+        if let Ok(instance) = sd_instance_query.get(entity) {
+            sd_object_buffer.push(instance_uniform(instance));
+            continue;
+        }
+
+        let Ok((shapes, modifier_stack, transform, some_mat_handle, some_sd_mat)) =
+            sdf_object_query.get(entity)
+        else {
+            warn!("Entity {:?} is operated on but is not an SdShape", entity);
+            sd_object_buffer.push(empty_uniform);
+            continue;
+        };
 
-        let nb_shape_field = shape.gpu_field_count();
         let start_field_data_index = current_field_data_index;
-        current_field_data_index += nb_shape_field;
+        let (shape, fields) = match shapes {
+            (Some(shape), _) => (
+                shape.uniform(start_field_data_index),
+                shape.flatten_fields_scaled(units.length_scale()),
+            ),
+            (None, Some(custom)) => {
+                let Some(descriptor) = shape_registry.get(custom.id) else {
+                    warn!(
+                        "Entity {:?} uses the unregistered custom shape {:?}",
+                        entity, custom.id
+                    );
+                    sd_object_buffer.push(empty_uniform);
+                    continue;
+                };
+                if custom.data.len() != descriptor.field_count {
+                    warn!(
+                        "Custom shape {} expects {} fields but entity {:?} has {}",
+                        descriptor.name,
+                        descriptor.field_count,
+                        entity,
+                        custom.data.len()
+                    );
+                }
+                // NOTE: The layout of custom data is unknown so SdUnits doesn't apply to it
+                let mut fields = custom.data.clone();
+                fields.resize(descriptor.field_count, 0.);
+                (
+                    custom.uniform(start_field_data_index, descriptor.field_count),
+                    fields,
+                )
+            }
+            (None, None) => unreachable!(),
+        };
+        current_field_data_index += fields.len();
 
-        for &field in shape.flatten_fields().iter() {
-            sd_field_data_buffer.push(field / 2.); // div by 2 is for matching with the blender
+        for &field in fields.iter() {
+            sd_field_data_buffer.push(field);
         }
 
-        let material = match (some_mat_handle, some_sd_mat) {
+        let material_index = match (some_mat_handle, some_sd_mat) {
             (Some(mat_handle), _) => {
                 let std_material = material_as.get(mat_handle.id()).unwrap_or_else(|| {
                     panic!("Material handle found but not available in Assets<StandardMaterial>")
                 });
-                SdMaterial::from(std_material.clone())
+                materials.insert_standard(mat_handle.id(), std_material)
             }
-            (None, Some(sd_mat)) => *sd_mat,
+            (None, Some(sd_mat)) => materials.insert(*sd_mat),
             (None, None) => {
                 panic!(
                     "Entity {:?} is missing both MeshMaterial3d and SdMaterial",
@@ -291,65 +600,167 @@ pub(crate) fn prepare_raymarch_buffer(
             rot: Vec3::from(transform.rotation().to_euler(EulerRot::XYZ)),
         };
 
-        // Push modifiers and count them
+        // Push modifiers and count them, custom payloads go after the shape fields
         let start_mod_index = current_mod_index;
-        for &modifier in modifier_stack.modifiers.iter().rev() {
-            current_mod_index = sd_mod_buffer.push(modifier.uniform()) + 1;
+        let mut compiled_mods = Vec::with_capacity(modifier_stack.modifiers.len());
+        for modifier in modifier_stack.modifiers.iter().rev() {
+            let data_index = current_field_data_index;
+            if let SdMod::Custom { id, data } = modifier {
+                // An unregistered modifier is left to the shader, which ignores it
+                let payload_size = match mod_registry.get(*id) {
+                    Some(descriptor) => {
+                        if data.len() != descriptor.payload_size {
+                            warn!(
+                                "Custom modifier {} expects {} values but entity {:?} has {}",
+                                descriptor.name,
+                                descriptor.payload_size,
+                                entity,
+                                data.len()
+                            );
+                        }
+                        descriptor.payload_size
+                    }
+                    None => {
+                        warn!(
+                            "Entity {:?} uses the unregistered custom modifier {:?}",
+                            entity, id
+                        );
+                        0
+                    }
+                };
+                for i in 0..payload_size {
+                    sd_field_data_buffer.push(data.get(i).copied().unwrap_or_default());
+                }
+                current_field_data_index += payload_size;
+            }
+            let uniform = modifier.uniform(data_index);
+            let mod_index = sd_mod_buffer.push(uniform);
+            compiled_mods.push((uniform.type_id, mod_index));
+            current_mod_index = mod_index + 1;
         }
 
-        sd_object_buffer.push(
-            SdObject {
-                shape,
-                material,
-                modifier_stack: modifier_stack.clone(),
-                transform,
-            }
-            .uniform(start_mod_index, start_field_data_index),
-        );
+        compiled_leaves[leaf_index] = SdCompiledLeaf::Shape {
+            type_id: shape.type_id(),
+            mods: compiled_mods,
+        };
 
-        let i = Some(current_shape_index);
-        current_shape_index += 1;
-        i
+        sd_object_buffer.push(SdObjectUniform {
+            shape,
+            material_index,
+            modifier_stack: modifier_stack.clone().uniform(start_mod_index),
+            transform: transform.uniform(),
+        });
+    }
+
+    // Op patients index the objects first, then the results of the previous ops
+    let patient_index = |patient: SdPatient| match patient {
+        SdPatient::Object(i) => i,
+        SdPatient::Op(i) => nb_shapes + i,
     };
 
-    for (&op, _index, op_on) in sd_op_query.iter().sort_unstable::<&SdIndex>().rev() {
-        let mut compute_index = |patient: Entity| -> Option<u16> {
-            if sd_op_query.get(patient).is_ok() {
-                let i = Some(nb_shapes + current_op_index);
-                current_op_index += 1;
-                i
-            } else {
-                push_object(patient)
+    // Custom blend params go after every object data
+    let mut push_blend_params = |op: SdBlend| {
+        let data_index = current_field_data_index;
+        if let SdBlend::Custom { id, params, .. } = op {
+            if blend_registry.get(id).is_none() {
+                warn!("Unregistered custom blend {:?} falls back to a union", id);
             }
-        };
+            for param in params.to_array() {
+                sd_field_data_buffer.push(param);
+            }
+            current_field_data_index += 4;
+        }
+        data_index
+    };
 
-        let args = op_on.clone().get_sd_argunments();
-        let lhs = compute_index(args.1).unwrap_or(0);
-        let rhs = compute_index(args.0).unwrap_or(0);
+    let mut compiled_ops = Vec::with_capacity(ops.len());
+    for &(op, lhs, rhs) in ops.iter() {
+        let (lhs, rhs) = (patient_index(lhs), patient_index(rhs));
+        let data_index = push_blend_params(op);
+        let uniform = SdOperator { op, lhs, rhs }.uniform(data_index);
+        compiled_ops.push((uniform.op.type_id(), lhs as u32, rhs as u32));
+        sd_op_buffer.push(uniform);
+    }
 
-        sd_op_buffer.push(SdOperator { op, lhs, rhs }.uniform());
+    // Prefab programs are flattened on their own so their op indices are already relative
+    for &(op, lhs, rhs) in prefab_ops.iter() {
+        let (lhs, rhs) = (patient_index(lhs), patient_index(rhs));
+        let data_index = push_blend_params(op);
+        sd_prefab_op_buffer.push(SdOperator { op, lhs, rhs }.uniform(data_index));
     }
 
     current_mod_index
         .eq(&0)
         .then(|| sd_mod_buffer.push(SdModUniform::default()));
 
+    prefab_ops.is_empty().then(|| {
+        sd_prefab_op_buffer.push(
+            SdOperator {
+                op: SdBlend::Union,
+                lhs: 0,
+                rhs: 0,
+            }
+            .uniform(0),
+        )
+    });
+
+    current_field_data_index
+        .eq(&0)
+        .then(|| sd_field_data_buffer.push(0.));
+
+    let has_volumes = !sd_volume_buffer.is_empty();
+    if !has_volumes {
+        sd_volume_buffer.push(SdVolumeUniform::default());
+    }
+
+    for &material in materials.uniforms() {
+        sd_material_buffer.push(material);
+    }
+
+    let compiled_map = (*map_mode == SdMapMode::Compiled).then(|| {
+        compiled_maps.get_or_compile(
+            compiled_map_wgsl(&compiled_leaves, &compiled_ops),
+            &mut shaders,
+        )
+    });
+
     sd_object_buffer.write_buffer(&device, &queue);
     sd_op_buffer.write_buffer(&device, &queue);
+    sd_prefab_op_buffer.write_buffer(&device, &queue);
     sd_mod_buffer.write_buffer(&device, &queue);
     sd_field_data_buffer.write_buffer(&device, &queue);
-
-    if let (Some(object_buf), Some(operator_buf), Some(modifier_buf), Some(field_data_buf)) = (
+    sd_material_buffer.write_buffer(&device, &queue);
+    sd_volume_buffer.write_buffer(&device, &queue);
+
+    if let (
+        Some(object_buf),
+        Some(operator_buf),
+        Some(prefab_operator_buf),
+        Some(modifier_buf),
+        Some(field_data_buf),
+        Some(material_buf),
+        Some(volume_buf),
+    ) = (
         sd_object_buffer.buffer(),
         sd_op_buffer.buffer(),
+        sd_prefab_op_buffer.buffer(),
         sd_mod_buffer.buffer(),
         sd_field_data_buffer.buffer(),
+        sd_material_buffer.buffer(),
+        sd_volume_buffer.buffer(),
     ) {
         commands.insert_resource(RayMarchBuffer {
             object: object_buf.clone(),
             operator: operator_buf.clone(),
+            prefab_operator: prefab_operator_buf.clone(),
             modifier: modifier_buf.clone(),
             field_data: field_data_buf.clone(),
+            material: material_buf.clone(),
+            volume: volume_buf.clone(),
+            volumes: has_volumes,
+            transmission: materials.has_transmission(),
+            max_prefab_ops,
+            compiled_map,
         });
     }
 }
@@ -0,0 +1,120 @@
+use bevy::{
+    ecs::{query::QueryItem, system::lifetimeless::Read},
+    pbr::{
+        ExtractedDirectionalLight, ExtractedPointLight, LightEntity, ShadowView, ViewLightEntities,
+    },
+    prelude::*,
+    render::{
+        extract_component::DynamicUniformIndex,
+        render_graph::{NodeRunError, RenderGraphContext, ViewNode},
+        render_resource::{BindGroupEntries, PipelineCache, RenderPassDescriptor, StoreOp},
+        renderer::RenderContext,
+        view::{ViewUniformOffset, ViewUniforms},
+    },
+};
+
+use crate::engine::{
+    buffer::{RayMarchBuffer, SD_MIN_PREFAB_OPS},
+    camera::{RayMarchCamera, RayMarchCameraUniform},
+    nodes::RayMarchEngineBindGroup,
+    pipeline::RayMarchEnginePipeline,
+};
+
+// Raymarches the SDFs into the shadow maps after bevy_pbr rendered the meshes in them,
+// meshes then receive SDF shadows like any other shadow caster
+#[derive(Default)]
+pub struct ShadowCasterNode;
+
+impl ViewNode for ShadowCasterNode {
+    type ViewQuery = (
+        Read<ViewLightEntities>,
+        Read<RayMarchCamera>,
+        Read<DynamicUniformIndex<RayMarchCameraUniform>>,
+    );
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        (view_lights, ray_march_settings, settings_index): QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        if !ray_march_settings.cast_shadow_maps {
+            return Ok(());
+        }
+        let (Some(bind_group), Some(view_binding)) = (
+            world.get_resource::<RayMarchEngineBindGroup>(),
+            world.resource::<ViewUniforms>().uniforms.binding(),
+        ) else {
+            return Ok(());
+        };
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let ray_march_pipeline = world.resource::<RayMarchEnginePipeline>();
+        let buffer = world.get_resource::<RayMarchBuffer>();
+        let max_prefab_ops = buffer.map_or(SD_MIN_PREFAB_OPS, |buffer| buffer.max_prefab_ops);
+        let pipeline = |hash| {
+            ray_march_pipeline
+                .shadow_caster_pipelines
+                .get(&(hash, max_prefab_ops))
+                .and_then(|&id| pipeline_cache.get_render_pipeline(id))
+        };
+
+        let Some(pipeline) = buffer
+            .and_then(|buffer| buffer.compiled_map.as_ref())
+            .and_then(|map| pipeline(Some(map.hash)))
+            .or_else(|| pipeline(None))
+        else {
+            return Ok(());
+        };
+
+        let view_bind_group = render_context.render_device().create_bind_group(
+            "raymarch_shadow_view_bind_group",
+            &pipeline_cache.get_bind_group_layout(&ray_march_pipeline.shadow_view_layout),
+            &BindGroupEntries::single(view_binding),
+        );
+
+        for &light_entity in view_lights.lights.iter() {
+            if !casts_shadows(world, light_entity) {
+                continue;
+            }
+            let (Some(shadow_view), Some(view_offset)) = (
+                world.get::<ShadowView>(light_entity),
+                world.get::<ViewUniformOffset>(light_entity),
+            ) else {
+                continue;
+            };
+
+            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("raymarch_shadow_caster_pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(
+                    shadow_view.depth_attachment.get_attachment(StoreOp::Store),
+                ),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            render_pass.set_render_pipeline(pipeline);
+            render_pass.set_bind_group(0, &view_bind_group, &[view_offset.offset]);
+            render_pass.set_bind_group(1, &bind_group.texture_bind_group, &[settings_index.index()]);
+            render_pass.set_bind_group(2, &bind_group.storage_bind_group, &[]);
+            render_pass.draw(0..3, 0..1);
+        }
+
+        Ok(())
+    }
+}
+
+// The light a shadow view renders for, the SDFs are only drawn in it if the light enables shadows
+fn casts_shadows(world: &World, light_view: Entity) -> bool {
+    match world.get::<LightEntity>(light_view) {
+        Some(LightEntity::Directional { light_entity, .. }) => world
+            .get::<ExtractedDirectionalLight>(*light_entity)
+            .is_some_and(|light| light.shadows_enabled),
+        Some(LightEntity::Point { light_entity, .. } | LightEntity::Spot { light_entity }) => world
+            .get::<ExtractedPointLight>(*light_entity)
+            .is_some_and(|light| light.shadows_enabled),
+        None => false,
+    }
+}
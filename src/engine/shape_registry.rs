@@ -0,0 +1,217 @@
+use bevy::prelude::*;
+
+use crate::engine::{
+    RAY_MARCH_SHAPES_HANDLE,
+    object::{SD_EMPTY_TYPE_ID, SdModStack, SdShapeUniform, gpu_shapes_wgsl},
+};
+
+// NOTE: Registered shapes get the type ids from here up to SD_EMPTY_TYPE_ID,
+// the SdShape variants must stay below it
+pub const SD_CUSTOM_SHAPE_TYPE_ID_START: u8 = 0x80;
+
+// Distance of a registered shape evaluated on the CPU, takes the sample point and the shape data
+pub type SdCustomShapeCpu = fn(Vec3, &[f32]) -> f32;
+
+// Type id of a shape added to the SdShapeRegistry
+#[derive(Reflect, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SdCustomShapeId(pub u8);
+
+#[derive(Clone)]
+pub struct SdCustomShapeDescriptor {
+    // Name of the WGSL function, it must be unique
+    pub name: String,
+    // WGSL source defining `fn <name>(p: vec3f, data_index: u32) -> f32`,
+    // the fields are read with `sd_field_data[data_index + i]`
+    pub wgsl: String,
+    pub field_count: usize,
+    pub cpu: Option<SdCustomShapeCpu>,
+}
+
+// Shapes defined by the app, they are spliced into the generated `select_shape_dist` switch.
+// Shapes can only be added so the type ids given out stay valid.
+#[derive(Resource, Default, Clone)]
+pub struct SdShapeRegistry {
+    shapes: Vec<SdCustomShapeDescriptor>,
+}
+
+impl SdShapeRegistry {
+    // The WGSL source can `#import bevy_sdf::bindings::sd_field_data` and anything from `bevy_sdf::utils`
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        wgsl: impl Into<String>,
+        field_count: usize,
+    ) -> SdCustomShapeId {
+        self.register_descriptor(SdCustomShapeDescriptor {
+            name: name.into(),
+            wgsl: wgsl.into(),
+            field_count,
+            cpu: None,
+        })
+    }
+
+    pub fn register_with_cpu(
+        &mut self,
+        name: impl Into<String>,
+        wgsl: impl Into<String>,
+        field_count: usize,
+        cpu: SdCustomShapeCpu,
+    ) -> SdCustomShapeId {
+        self.register_descriptor(SdCustomShapeDescriptor {
+            name: name.into(),
+            wgsl: wgsl.into(),
+            field_count,
+            cpu: Some(cpu),
+        })
+    }
+
+    pub fn register_descriptor(&mut self, descriptor: SdCustomShapeDescriptor) -> SdCustomShapeId {
+        let type_id = SD_CUSTOM_SHAPE_TYPE_ID_START as usize + self.shapes.len();
+        assert!(
+            type_id < SD_EMPTY_TYPE_ID as usize,
+            "SdShapeRegistry is full"
+        );
+        assert!(
+            descriptor.field_count <= u8::MAX as usize,
+            "Custom shape {} has more than {} fields",
+            descriptor.name,
+            u8::MAX
+        );
+        assert!(
+            self.shapes.iter().all(|s| s.name != descriptor.name),
+            "Custom shape {} is already registered",
+            descriptor.name
+        );
+
+        self.shapes.push(descriptor);
+        SdCustomShapeId(type_id as u8)
+    }
+
+    pub fn get(&self, id: SdCustomShapeId) -> Option<&SdCustomShapeDescriptor> {
+        self.shapes
+            .get(id.0.checked_sub(SD_CUSTOM_SHAPE_TYPE_ID_START)? as usize)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (SdCustomShapeId, &SdCustomShapeDescriptor)> {
+        self.shapes
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (SdCustomShapeId(SD_CUSTOM_SHAPE_TYPE_ID_START + i as u8), s))
+    }
+
+    // CPU distance of a registered shape, None if it has no CPU twin
+    pub fn distance(&self, id: SdCustomShapeId, p: Vec3, data: &[f32]) -> Option<f32> {
+        self.get(id)?.cpu.map(|cpu| cpu(p, data))
+    }
+
+    pub(crate) fn imports_wgsl(&self) -> String {
+        self.shapes
+            .iter()
+            .map(|s| format!("#import bevy_sdf::custom_shapes::{0}::{0}\n", s.name))
+            .collect()
+    }
+
+    pub(crate) fn select_arms_wgsl(&self) -> String {
+        self.iter()
+            .map(|(id, s)| {
+                format!(
+                    "        case {}u {{\n            return {}(p, data_index);\n        }}\n",
+                    id.0, s.name
+                )
+            })
+            .collect()
+    }
+}
+
+// A leaf using a shape of the SdShapeRegistry, to use instead of an SdShape
+#[derive(Component, Reflect, Debug, Clone)]
+#[require(Name::new("SdObject"), SdModStack, Transform, GlobalTransform)]
+#[reflect(Component)]
+pub struct SdCustomShape {
+    pub id: SdCustomShapeId,
+    pub data: Vec<f32>,
+}
+
+impl SdCustomShape {
+    pub fn new(id: SdCustomShapeId, data: impl Into<Vec<f32>>) -> Self {
+        Self {
+            id,
+            data: data.into(),
+        }
+    }
+
+    #[inline]
+    pub fn uniform(&self, index: usize, field_count: usize) -> SdShapeUniform {
+        SdShapeUniform::new(self.id.0, index, field_count)
+    }
+}
+
+// Every registered shape is its own shader library imported by the generated `bevy_sdf::shapes`
+pub(crate) fn update_custom_shape_shaders(
+    registry: Res<SdShapeRegistry>,
+    mut shaders: ResMut<Assets<Shader>>,
+    mut handles: Local<Vec<Handle<Shader>>>,
+) {
+    // The registry only grows, the libraries already added are kept as they are
+    for shape in registry.shapes.iter().skip(handles.len()) {
+        handles.push(shaders.add(Shader::from_wgsl(
+            format!(
+                "#define_import_path bevy_sdf::custom_shapes::{}\n\n{}",
+                shape.name, shape.wgsl
+            ),
+            format!("bevy_sdf/custom_shapes/{}.wgsl", shape.name),
+        )));
+    }
+
+    shaders
+        .insert(
+            RAY_MARCH_SHAPES_HANDLE.id(),
+            Shader::from_wgsl(gpu_shapes_wgsl(&registry), "bevy_sdf/shapes.wgsl"),
+        )
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(registry: &mut SdShapeRegistry, count: usize) -> Vec<SdCustomShapeId> {
+        (0..count)
+            .map(|i| registry.register(format!("shape_{i}"), "", 1))
+            .collect()
+    }
+
+    #[test]
+    fn ids_stop_below_reserved_type_ids() {
+        let mut registry = SdShapeRegistry::default();
+        let ids = fill(
+            &mut registry,
+            (SD_EMPTY_TYPE_ID - SD_CUSTOM_SHAPE_TYPE_ID_START) as usize,
+        );
+        assert_eq!(ids[0], SdCustomShapeId(SD_CUSTOM_SHAPE_TYPE_ID_START));
+        assert_eq!(ids[ids.len() - 1], SdCustomShapeId(SD_EMPTY_TYPE_ID - 1));
+        assert!(ids.iter().all(|&id| registry.get(id).is_some()));
+    }
+
+    #[test]
+    #[should_panic(expected = "SdShapeRegistry is full")]
+    fn registering_past_reserved_type_ids_panics() {
+        let mut registry = SdShapeRegistry::default();
+        fill(
+            &mut registry,
+            (SD_EMPTY_TYPE_ID - SD_CUSTOM_SHAPE_TYPE_ID_START) as usize + 1,
+        );
+    }
+
+    #[test]
+    fn unknown_ids_are_not_found() {
+        let mut registry = SdShapeRegistry::default();
+        fill(&mut registry, 1);
+        assert!(registry.get(SdCustomShapeId(0)).is_none());
+        assert!(
+            registry
+                .get(SdCustomShapeId(SD_CUSTOM_SHAPE_TYPE_ID_START + 1))
+                .is_none()
+        );
+    }
+}
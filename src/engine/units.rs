@@ -0,0 +1,61 @@
+use bevy::prelude::*;
+
+// How the lengths of an SdShape are read when they are sent to the GPU, the fields marked `#[gpu(scale)]`.
+// As a resource it is the default of every tree, as a component on a root SdBlend
+// (or an SdPrefab) it overrides the resource for that tree only.
+#[derive(Resource, Component, Reflect, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[reflect(Resource, Component, Default)]
+pub enum SdUnits {
+    // `SdShape::Sphere { radius: 1.0 }` has a radius of 1
+    #[default]
+    Literal,
+    // Lengths are halved to match the sizes of a Blender scene exported with skein
+    Blender,
+}
+
+impl SdUnits {
+    #[inline]
+    pub fn length_scale(self) -> f32 {
+        match self {
+            Self::Literal => 1.,
+            Self::Blender => 0.5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::object::SdShape;
+
+    #[test]
+    fn literal_units_keep_lengths() {
+        let shape = SdShape::Sphere { radius: 2. };
+        assert_eq!(
+            shape.flatten_fields_scaled(SdUnits::Literal.length_scale()),
+            shape.flatten_fields()
+        );
+    }
+
+    #[test]
+    fn blender_units_halve_lengths_only() {
+        let scale = SdUnits::Blender.length_scale();
+        assert_eq!(
+            SdShape::Sphere { radius: 2. }.flatten_fields_scaled(scale),
+            vec![1.]
+        );
+        assert_eq!(
+            SdShape::RoundBox {
+                bounds: Vec3::new(2., 4., 6.),
+                radius: 1.,
+            }
+            .flatten_fields_scaled(scale),
+            vec![1., 2., 3., 0.5]
+        );
+        // Not a length
+        assert_eq!(
+            SdShape::Gyroid { height: 2. }.flatten_fields_scaled(scale),
+            vec![2.]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use bevy::{prelude::*, render::render_resource::ShaderType};
+
+use crate::engine::object::SdShapeUniform;
+
+// NOTE: Shape type id reserved for volumes, it must match `SD_VOLUME_TYPE_ID` in types.wgsl
+pub const SD_VOLUME_TYPE_ID: u8 = 0xFE;
+
+// Renders the SdShape or SdBlend subtree it is on as participating media instead of a surface.
+// Inside the SDF the negated distance becomes a density reaching `density` at `falloff` deep,
+// the subtree is empty space for the SdBlend operating on it.
+// WARN: Volumes are not instanced, one inside a prefab is rendered once where its shapes are
+#[derive(Component, Reflect, Debug, Clone, Copy)]
+#[reflect(Component, Default)]
+pub struct SdVolume {
+    // Extinction per unit of distance once `falloff` inside the SDF
+    pub density: f32,
+    pub falloff: f32,
+    // Share of the extinction that scatters light instead of absorbing it
+    pub albedo: Color,
+    // Henyey-Greenstein asymmetry, positive values scatter forward like clouds lit from behind
+    pub anisotropy: f32,
+}
+
+impl Default for SdVolume {
+    fn default() -> Self {
+        Self {
+            density: 1.,
+            falloff: 0.5,
+            albedo: Color::WHITE,
+            anisotropy: 0.2,
+        }
+    }
+}
+
+#[derive(ShaderType, Clone, Copy, Default)]
+pub struct SdVolumeUniform {
+    // Index in sd_object of the volume, its shape is the range of its program in sd_prefab_ops
+    pub object: u32,
+    pub density: f32,
+    pub falloff: f32,
+    pub anisotropy: f32,
+    pub albedo: Vec3,
+}
+
+impl SdVolume {
+    #[inline]
+    pub fn uniform(self, object: usize) -> SdVolumeUniform {
+        SdVolumeUniform {
+            object: object as u32,
+            density: self.density,
+            falloff: self.falloff,
+            anisotropy: self.anisotropy,
+            albedo: self.albedo.to_linear().to_vec3(),
+        }
+    }
+}
+
+impl SdShapeUniform {
+    // Packs the volume program range the same way as an instance
+    #[inline]
+    pub fn volume(op_start: usize, op_len: usize) -> Self {
+        Self::new(SD_VOLUME_TYPE_ID, op_start, op_len)
+    }
+}
@@ -4,7 +4,9 @@
     // SDF Object-related
     SdObjectPacked,
     SdOperatorPacked,
-    SdMod
+    SdMod,
+    SdMaterialPacked,
+    SdVolume,
 }
 
 @group(1) @binding(0) var depth_texture: texture_depth_2d;
@@ -18,17 +20,34 @@ struct RayMarchCamera {
     shadow_max_steps: u32,
     shadow_max_distance: f32,
     shadow_softness: f32,
-    normal_eps: f32
+    normal_eps: f32,
+    environment_map_transform: mat4x4f,
+    environment_map_intensity: f32,
+    reflection_bounces: u32,
+    reflection_max_roughness: f32,
+    transmission_steps: u32,
+    volume_max_steps: u32,
+    volume_step_size: f32,
+    volume_light_steps: u32,
 }
 @group(1) @binding(1) var<uniform> settings: RayMarchCamera;
+// EnvironmentMapLight of the view, fallback cubemaps without SD_ENVIRONMENT_MAP
+@group(1) @binding(2) var diffuse_environment_map: texture_cube<f32>;
+@group(1) @binding(3) var specular_environment_map: texture_cube<f32>;
+@group(1) @binding(4) var environment_map_sampler: sampler;
 
 // PERF: seperate the sd_object buffer into multiple buffers for more performance
 @group(2) @binding(0) var<storage, read> sd_object: array<SdObjectPacked>;
 @group(2) @binding(1) var<storage, read> sd_ops: array<SdOperatorPacked>;
 @group(2) @binding(2) var<storage, read> sd_mod: array<SdMod>;
 @group(2) @binding(3) var<storage, read> sd_field_data: array<f32>;
+@group(2) @binding(4) var<storage, read> sd_prefab_ops: array<SdOperatorPacked>;
+@group(2) @binding(5) var<storage, read> sd_materials: array<SdMaterialPacked>;
+@group(2) @binding(6) var<storage, read> sd_volumes: array<SdVolume>;
 
 @group(3) @binding(0) var depth_prepass: texture_storage_2d<r32float, read_write>;
 @group(3) @binding(1) var normal_prepass: texture_storage_2d<rgba16float, write>;
 @group(3) @binding(2) var mask_prepass: texture_storage_2d<r16float, write>;
 @group(3) @binding(3) var material_prepass: texture_storage_2d<rgba16float, read_write>;
+// Opaque meshes of the view, read behind transmissive SDFs
+@group(3) @binding(4) var view_main_texture: texture_2d<f32>;
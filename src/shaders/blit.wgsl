@@ -15,6 +15,17 @@ fn fragment(in: FullscreenVertexOutput) -> FragmentOutput {
     let raymarch_depth = textureSample(raymarch_depth_texture, texture_sampler, in.uv).x;
     let raymarch_output = textureSample(raymarch_output_texture, texture_sampler, in.uv);
 
+    // Translucent pixels are blended without writing depth, like transparent meshes
+#ifdef SD_TRANSLUCENT
+    if raymarch_output.a >= 1.0 {
+        discard;
+    }
+#else
+    if raymarch_output.a < 1.0 {
+        discard;
+    }
+#endif
+
     return FragmentOutput(
         raymarch_output, 
         raymarch_depth
@@ -1,36 +1,72 @@
 // Ref : https://compute.toys/view/407
 
-#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#define_import_path bevy_sdf::ray_march
+
 #import bevy_pbr::mesh_view_bindings::{view, lights, clusterable_objects}
-#import bevy_pbr::lighting::getDistanceAttenuation
-#import bevy_pbr::mesh_view_types::ClusterableObject
+#import bevy_pbr::lighting::{
+    LightingInput,
+    LAYER_BASE,
+    getDistanceAttenuation,
+    perceptualRoughnessToRoughness,
+    F_AB,
+    point_light,
+    spot_light,
+    directional_light,
+}
+#import bevy_pbr::ambient::ambient_light
+#import bevy_pbr::environment_map::radiance_sample_direction
+#import bevy_pbr::shadows::{fetch_point_shadow, fetch_spot_shadow, fetch_directional_shadow}
+#import bevy_render::maths::PI
+#import bevy_pbr::mesh_view_types::{
+    ClusterableObject,
+    POINT_LIGHT_FLAGS_SPOT_LIGHT_Y_NEGATIVE,
+    POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT,
+    DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT,
+}
 #import bevy_render::view::position_world_to_ndc
 
 #import bevy_sdf::bindings::{
     screen_texture,
     depth_texture,
     settings,
+    diffuse_environment_map,
+    specular_environment_map,
+    environment_map_sampler,
 
     sd_object,
     sd_ops,
+    sd_prefab_ops,
     sd_mod,
+    sd_materials,
+    sd_volumes,
 
     depth_prepass,
     normal_prepass,
     mask_prepass,
     material_prepass,
+    view_main_texture,
 };
-#import bevy_sdf::selectors::{select_shape, select_blend};
+#import bevy_sdf::selectors::{select_shape, select_blend, apply_transform};
 #import bevy_sdf::types::{
     // SDF Object-related
+    SD_INSTANCE_TYPE_ID,
+    SD_VOLUME_TYPE_ID,
+    SD_EMPTY_TYPE_ID,
     SdObject,
     SdObjectPacked,
     unpack_sd_object,
 
     // SDF Material-related
     SdMaterial,
-    SdMaterialPacked,
+    SdMaterialMix,
+    single_material,
     unpack_sd_material,
+    SD_MATERIAL_FLAGS_UNLIT,
+    SD_MATERIAL_ALPHA_MODE_OPAQUE,
+    SD_MATERIAL_ALPHA_MODE_MASK,
+    SD_MATERIAL_ALPHA_MODE_BLEND,
+    SD_MATERIAL_ALPHA_MODE_ADD,
+    sd_material_alpha_mode,
 
     // SDF Modifiers
     SdMod,
@@ -45,9 +81,6 @@
 
     // Distance info
     DistanceInfo,
-    DistanceInfoPacked,
-    pack_distance_info,
-    unpack_distance_info,
 
     // Marching result
     MarchOutput,
@@ -55,15 +88,74 @@
 
 // PERF: Make op_resut recyce te sapce in te array to get a significant perforamce boost when dealing with large amounts of OPS
 const MAX_OPS: u32 = 8;
-var<private> op_results: array<DistanceInfoPacked, MAX_OPS>;
+var<private> op_results: array<DistanceInfo, MAX_OPS>;
+
+// Sized from the longest instance or volume program, see RayMarchBuffer::max_prefab_ops
+#ifdef SD_MAX_PREFAB_OPS
+const MAX_PREFAB_OPS: u32 = #{SD_MAX_PREFAB_OPS}u;
+#else
+const MAX_PREFAB_OPS: u32 = 8u;
+#endif
+var<private> prefab_results: array<DistanceInfo, MAX_PREFAB_OPS>;
+
+const EMPTY_DIST: f32 = 1e10;
 
 fn shape_to_dist(obj: SdObject, p: vec3f) -> DistanceInfo {
     let dist = select_shape(p, obj.shape, obj.transform, obj.modifiers);
-    return DistanceInfo(dist, obj.material);
+    return DistanceInfo(dist, single_material(obj.material));
+}
+
+fn mix_material(a: SdMaterial, b: SdMaterial, t: f32) -> SdMaterial {
+    return SdMaterial(mix(a.color, b.color, t), mix(a.roughness, b.roughness, t), mix(a.fresnel, b.fresnel, t), mix(a.metallic, b.metallic, t), mix(a.sss_strength, b.sss_strength, t), mix(a.sss_radius, b.sss_radius, t), mix(a.emissive, b.emissive, t), mix(a.emissive_exposure_weight, b.emissive_exposure_weight, t), mix(a.reflectance, b.reflectance, t), mix(a.specular_tint, b.specular_tint, t), mix(a.specular_transmission, b.specular_transmission, t), mix(a.ior, b.ior, t), mix(a.absorption, b.absorption, t), select(a.flags, b.flags, t > 0.5), select(a.alpha_cutoff, b.alpha_cutoff, t > 0.5));
 }
 
-fn blend_material(a: SdMaterial, b: SdMaterial, m: f32) -> SdMaterial {
-    return SdMaterial(mix(b.color, a.color, m), mix(b.roughness, a.roughness, m), mix(b.fresnel, a.fresnel, m), mix(b.metallic, a.metallic, m), mix(b.sss_strength, a.sss_strength, m), mix(b.sss_radius, a.sss_radius, m));
+// Reads the materials of a mix from sd_materials, only done once per shaded point
+fn resolve_material(material: SdMaterialMix) -> SdMaterial {
+    let a = unpack_sd_material(sd_materials[material.a]);
+    if material.t <= 0.0 || material.a == material.b {
+        return a;
+    }
+    return mix_material(a, unpack_sd_material(sd_materials[material.b]), material.t);
+}
+
+// `m` is the weight of `a`. A mix only holds two materials so the two heaviest of both sides are kept.
+fn blend_material(a: SdMaterialMix, b: SdMaterialMix, m: f32) -> SdMaterialMix {
+    if m >= 1.0 {
+        return a;
+    }
+    if m <= 0.0 {
+        return b;
+    }
+
+    let ids = array<u32, 4>(a.a, a.b, b.a, b.b);
+    var weights = array<f32, 4>(m * (1.0 - a.t), m * a.t, (1.0 - m) * (1.0 - b.t), (1.0 - m) * b.t);
+
+    // A material on both sides is a single candidate
+    for (var i = 1u; i < 4u; i++) {
+        for (var j = 0u; j < i; j++) {
+            if ids[j] == ids[i] {
+                weights[j] += weights[i];
+                weights[i] = 0.0;
+                break;
+            }
+        }
+    }
+
+    var first = 0u;
+    for (var i = 1u; i < 4u; i++) {
+        if weights[i] > weights[first] {
+            first = i;
+        }
+    }
+    var second = select(0u, 1u, first == 0u);
+    for (var i = 0u; i < 4u; i++) {
+        if i != first && weights[i] > weights[second] {
+            second = i;
+        }
+    }
+
+    let total = weights[first] + weights[second];
+    return SdMaterialMix(ids[first], ids[second], select(0.0, weights[second] / total, total > 0.0));
 }
 
 fn blend_distance_info(a: DistanceInfo, b: DistanceInfo, op: SdBlend) -> DistanceInfo {
@@ -74,7 +166,67 @@ fn blend_distance_info(a: DistanceInfo, b: DistanceInfo, op: SdBlend) -> Distanc
     return DistanceInfo(d_blend, blend_material(a.material, b.material, m));
 }
 
-fn map(p: vec3f) -> DistanceInfo {
+fn object_to_dist(index: u32, p: vec3f) -> DistanceInfo {
+    let obj = unpack_sd_object(sd_object[index]);
+    if obj.shape.type_id == SD_INSTANCE_TYPE_ID {
+        return instance_to_dist(obj, p);
+    }
+    // Volumes are only read as density, see sample_volumes
+    if obj.shape.type_id == SD_VOLUME_TYPE_ID || obj.shape.type_id == SD_EMPTY_TYPE_ID {
+        return DistanceInfo(EMPTY_DIST, single_material(obj.material));
+    }
+    return shape_to_dist(obj, p);
+}
+
+// Evaluates the prefab program of an instance with the sample point moved into the prefab space
+fn instance_to_dist(obj: SdObject, p: vec3f) -> DistanceInfo {
+    let n_shapes = arrayLength(&sd_object);
+    let start = obj.shape.data_index;
+    let len = obj.shape.len;
+
+    if len == 0u {
+        return DistanceInfo(EMPTY_DIST, single_material(obj.material));
+    }
+
+    let local_p = apply_transform(p, obj.transform);
+
+    for (var e = 0u; e < len; e++) {
+        let op = unpack_sd_operator(sd_prefab_ops[start + e]);
+        var lhs_info: DistanceInfo;
+        var rhs_info: DistanceInfo;
+
+        if op.lhs < n_shapes {
+            lhs_info = prefab_shape_to_dist(op.lhs, local_p);
+        } else {
+            lhs_info = prefab_results[op.lhs - n_shapes];
+        }
+
+        if op.rhs < n_shapes {
+            rhs_info = prefab_shape_to_dist(op.rhs, local_p);
+        } else {
+            rhs_info = prefab_results[op.rhs - n_shapes];
+        }
+
+        let result = blend_distance_info(lhs_info, rhs_info, op.op);
+        prefab_results[e] = result;
+    }
+
+    return prefab_results[len - 1u];
+}
+
+// NOTE: WGSL has no recursion so instances nested in a prefab are left empty, volumes too.
+// Every type id from SD_EMPTY_TYPE_ID up is a leaf that is not a shape
+fn prefab_shape_to_dist(index: u32, p: vec3f) -> DistanceInfo {
+    let obj = unpack_sd_object(sd_object[index]);
+    if obj.shape.type_id >= SD_EMPTY_TYPE_ID {
+        return DistanceInfo(EMPTY_DIST, single_material(obj.material));
+    }
+    return shape_to_dist(obj, p);
+}
+
+virtual fn map(p: vec3f) -> DistanceInfo {
+    // NOTE: A compiled map overrides it with the ops of the current tree written out, see SdMapMode.
+    // The comment can't go above, naga_oil joins the lines before `virtual fn`
     let n_shapes = arrayLength(&sd_object);
     let n_ops = arrayLength(&sd_ops);
 
@@ -85,30 +237,30 @@ fn map(p: vec3f) -> DistanceInfo {
 
         // Handle LHS
         if op.lhs < n_shapes {
-            lhs_info = shape_to_dist(unpack_sd_object(sd_object[op.lhs]), p);
+            lhs_info = object_to_dist(op.lhs, p);
         } else {
-            lhs_info = unpack_distance_info(op_results[op.lhs - n_shapes]);
+            lhs_info = op_results[op.lhs - n_shapes];
         }
 
         // Handle RHS
         if op.rhs < n_shapes {
-            rhs_info = shape_to_dist(unpack_sd_object(sd_object[op.rhs]), p);
+            rhs_info = object_to_dist(op.rhs, p);
         } else {
-            rhs_info = unpack_distance_info(op_results[op.rhs - n_shapes]);
+            rhs_info = op_results[op.rhs - n_shapes];
         }
 
         // Blend both sides using the current op
         let result = blend_distance_info(lhs_info, rhs_info, op.op);
-        op_results[e] = pack_distance_info(result);
+        op_results[e] = result;
     }
 
-    return unpack_distance_info(op_results[n_ops - 1u]);
+    return op_results[n_ops - 1u];
 }
 
 fn march(ro: vec3f, rd: vec3f) -> MarchOutput {
     var t: f32 = 0.0;
     var p: vec3f = ro;
-    var mat: SdMaterial;
+    var mat: SdMaterialMix;
 
     let eps = settings.eps;     
     let maxDist = settings.max_distance;
@@ -126,7 +278,7 @@ fn march(ro: vec3f, rd: vec3f) -> MarchOutput {
                 normal(p2),
                 p2,
                 t - (0.5 * eps),
-                mat
+                resolve_material(mat)
             );
         }
         if t > maxDist {
@@ -134,7 +286,7 @@ fn march(ro: vec3f, rd: vec3f) -> MarchOutput {
                 vec3f(0.0), 
                 p,
                 t,
-                mat
+                resolve_material(mat)
             );
         }
         t += d;  
@@ -144,7 +296,7 @@ fn march(ro: vec3f, rd: vec3f) -> MarchOutput {
         vec3f(0.0),
         p,
         t,
-        mat
+        resolve_material(mat)
     );
 }
 
@@ -194,6 +346,20 @@ fn normal(p: vec3f) -> vec3f {
     );
 }
 
+// Visibility of a light along `rd`, the technique comes from the SdShadowMode of the camera
+fn sd_shadow(ro: vec3f, rd: vec3f, max_dist: f32) -> f32 {
+#ifdef SD_SHADOW_HARD
+    return shadow(ro, rd, settings.shadow_eps, max_dist, settings.shadow_max_steps);
+#else ifdef SD_SHADOW_SOFT
+    return softshadow(ro, rd, settings.shadow_eps, max_dist, settings.shadow_max_steps, settings.shadow_softness);
+#else ifdef SD_SHADOW_IMPROVED_SOFT
+    return improved_softshadow(ro, rd, settings.shadow_eps, max_dist, settings.shadow_max_steps, settings.shadow_softness);
+#else
+    return 1.0;
+#endif
+}
+
+#ifdef SD_SHADOW_SOFT
 fn softshadow(
     ro: vec3f,
     rd: vec3f,
@@ -219,6 +385,40 @@ fn softshadow(
     return 0.25 * (1.0 + res) * (1.0 + res) * (2.0 - res);
 }
 
+#endif
+
+#ifdef SD_SHADOW_IMPROVED_SOFT
+// NOTE: https://iquilezles.org/articles/rmshadows, triangulates the closest point
+// between the last two steps so the penumbra doesn't band when t grows
+fn improved_softshadow(
+    ro: vec3f,
+    rd: vec3f,
+    eps: f32,
+    max_dist: f32,
+    max_steps: u32,
+    softness: f32
+) -> f32 {
+    var res = 1.0;
+    var t = eps;
+    var ph = 1e20;
+
+    for (var i = 0u; i < max_steps && t < max_dist; i++) {
+        let h = map(ro + rd * t).dist;
+        if h < 0.001 {
+            return 0.0;
+        }
+        let y = h * h / (2.0 * ph);
+        let d = sqrt(max(h * h - y * y, 0.0));
+        res = min(res, d / (softness * max(0.0, t - y)));
+        ph = h;
+        t += h;
+    }
+
+    return saturate(res);
+}
+#endif
+
+#ifdef SD_SHADOW_HARD
 fn shadow(
     ro: vec3f,
     rd: vec3f,
@@ -236,6 +436,7 @@ fn shadow(
     }
     return 1.0;
 }
+#endif
 
 fn calc_ao(p: vec3f, n: vec3f) -> f32 {
     var ao = 0.0;
@@ -255,22 +456,332 @@ fn apply_lighting(
     normal: vec3f,
     material: SdMaterial,
 ) -> vec3f {
-    var result: vec3f = vec3f(0.0);
+    if (material.flags & SD_MATERIAL_FLAGS_UNLIT) != 0u {
+        return material.color.rgb;
+    }
 
+    var input = sd_lighting_input(ro, rd, normal, material);
+
+    // === Reflections ===
+    let N = input.layers[LAYER_BASE].N;
+    let sample_dir = radiance_sample_direction(N, input.layers[LAYER_BASE].R, input.layers[LAYER_BASE].roughness);
+    var radiance = environment_radiance(sample_dir, input.layers[LAYER_BASE].perceptual_roughness);
+#ifdef SD_REFLECTIONS
+    radiance = trace_reflections(&input, radiance);
+#endif
+
+    return light_surface(&input, material, radiance);
+}
+
+// Everything but the reflections, `radiance` is the light the surface reflects
+fn light_surface(
+    input: ptr<function, LightingInput>,
+    material: SdMaterial,
+    radiance: vec3f,
+) -> vec3f {
     // === Ambient light ===
-    result += apply_ambient(material);
+    let ao = calc_ao((*input).P, (*input).layers[LAYER_BASE].N);
+    var result = apply_ambient(input, material, ao);
+
+    // === Image based lighting ===
+    result += apply_image_based_light(input, radiance, ao);
+
+    // === Loop over all directional lights ===
+    for (var i = 0u; i < lights.n_directional_lights; i++) {
+        result += apply_directional_light_contribution(i, input, material);
+    }
 
     // === Loop over all clusterable lights ===
     for (var i = 0u; i < arrayLength(&clusterable_objects.data); i++) {
-        result += apply_light_contribution(i, ro, rd, normal, material);
+        result += apply_light_contribution(i, input, material);
     }
 
-    return result;
+    // === Emissive ===
+    // Same as bevy_pbr, the lights go through the view exposure
+    return view.exposure * result + apply_emissive(material);
+}
+
+#ifdef SD_REFLECTIONS
+// Follows the mirror direction through `map()` for up to reflection_bounces surfaces,
+// rough surfaces and rays that escape keep reading the environment map
+fn trace_reflections(input: ptr<function, LightingInput>, fallback: vec3f) -> vec3f {
+    if (*input).layers[LAYER_BASE].perceptual_roughness > settings.reflection_max_roughness {
+        return fallback;
+    }
+
+    var p = (*input).P;
+    var normal = (*input).layers[LAYER_BASE].N;
+    var rd = (*input).layers[LAYER_BASE].R;
+    var perceptual_roughness = (*input).layers[LAYER_BASE].perceptual_roughness;
+    var radiance = vec3f(0.0);
+    var throughput = vec3f(1.0);
+
+    for (var bounce = 0u; bounce < settings.reflection_bounces; bounce++) {
+        let m = march(p + normal * settings.shadow_eps, rd);
+        if m.depth > settings.max_distance || is_alpha_masked(m.material) {
+            return radiance + throughput * environment_radiance(rd, perceptual_roughness);
+        }
+        if (m.material.flags & SD_MATERIAL_FLAGS_UNLIT) != 0u {
+            return radiance + throughput * m.material.color.rgb;
+        }
+
+        var hit = sd_lighting_input(m.pos, rd, m.normal, m.material);
+        perceptual_roughness = hit.layers[LAYER_BASE].perceptual_roughness;
+
+        // The radiance is exposed again by the surface that reflects it
+        if bounce + 1u == settings.reflection_bounces || perceptual_roughness > settings.reflection_max_roughness {
+            let sample_dir = radiance_sample_direction(m.normal, hit.layers[LAYER_BASE].R, hit.layers[LAYER_BASE].roughness);
+            let hit_radiance = environment_radiance(sample_dir, perceptual_roughness);
+            return radiance + throughput * light_surface(&hit, m.material, hit_radiance) / view.exposure;
+        }
+        radiance += throughput * light_surface(&hit, m.material, vec3f(0.0)) / view.exposure;
+
+        throughput *= environment_specular(&hit);
+        p = m.pos;
+        normal = m.normal;
+        rd = hit.layers[LAYER_BASE].R;
+    }
+
+    return radiance;
+}
+#endif
+
+// Distance along the ray to the meshes of the depth prepass
+fn mesh_distance(id: vec2u, uv: vec2f, ro: vec3f) -> f32 {
+    let depth = textureLoad(depth_texture, vec2u(vec2f(id) / settings.depth_scale), 0);
+    if depth <= 0.0 {
+        return settings.max_distance;
+    }
+    let world = view.world_from_clip * vec4f(uv, depth, 1.0);
+    return min(distance(world.xyz / world.w, ro), settings.max_distance);
+}
+
+#ifdef SD_TRANSMISSION
+// Follows the refracted ray through up to transmission_steps transparent surfaces and returns the light
+// coming through, `background_t` is the distance to the meshes behind along the primary ray
+fn trace_transmission(m: MarchOutput, rd: vec3f, background_t: f32) -> vec3f {
+    var hit = m;
+    var dir = rd;
+    var radiance = vec3f(0.0);
+    var throughput = vec3f(1.0);
+
+    for (var step = 0u; step < settings.transmission_steps; step++) {
+        let material = hit.material;
+        throughput *= transmission_weight(material, dir, hit.normal);
+
+        let inside = march_interior(hit.pos - hit.normal * settings.shadow_eps, refract(dir, hit.normal, 1.0 / material.ior), material.ior);
+        // Beer-Lambert over the path inside
+        throughput *= exp(-material.absorption * inside.length);
+        dir = inside.dir;
+
+        hit = march(inside.pos + inside.normal * settings.shadow_eps, dir);
+        if hit.depth > settings.max_distance || is_alpha_masked(hit.material) {
+            let behind = max(background_t - m.depth, 0.0);
+            return radiance + throughput * transmitted_background(inside.pos, dir, behind);
+        }
+        radiance += throughput * apply_lighting(hit.pos, dir, hit.normal, hit.material);
+        if hit.material.specular_transmission <= 0.0 {
+            break;
+        }
+    }
+
+    return radiance;
+}
+
+// Like the transmission of bevy_pbr, what is behind is read from the main texture of the view
+// where the exit ray reaches the depth of the meshes behind the surface
+fn transmitted_background(p: vec3f, dir: vec3f, distance: f32) -> vec3f {
+    var clip = view.clip_from_world * vec4f(p + dir * distance, 1.0);
+    if clip.w <= 0.0 {
+        clip = view.clip_from_world * vec4f(p, 1.0);
+    }
+    let ndc = clip.xy / clip.w;
+    let uv = vec2f(ndc.x, -ndc.y) * 0.5 + 0.5;
+    let texel = clamp(view.viewport.xy + uv * view.viewport.zw, vec2f(0.0), vec2f(textureDimensions(view_main_texture) - 1u));
+    return textureLoad(view_main_texture, vec2u(texel), 0).rgb;
+}
+
+// Share of the light refracted into the surface, the Fresnel reflection stays with the specular
+fn transmission_weight(material: SdMaterial, rd: vec3f, normal: vec3f) -> vec3f {
+    let r = (material.ior - 1.0) / (material.ior + 1.0);
+    let f0 = r * r;
+    let fresnel = f0 + (1.0 - f0) * pow(1.0 - saturate(dot(-rd, normal)), 5.0);
+    return material.specular_transmission * (1.0 - material.metallic) * (1.0 - fresnel) * material.color.rgb;
+}
+
+const MAX_INTERNAL_REFLECTIONS: u32 = 4;
+
+struct InteriorOutput {
+    pos: vec3f,
+    // Refracted out of the surface
+    dir: vec3f,
+    // Outward normal at the exit
+    normal: vec3f,
+    length: f32,
+}
+
+// Marches the inside of a surface with the negated distance until the ray refracts out,
+// totally reflected rays bounce inside up to MAX_INTERNAL_REFLECTIONS times
+fn march_interior(ro: vec3f, rd: vec3f, ior: f32) -> InteriorOutput {
+    var p = ro;
+    var dir = rd;
+    var length = 0.0;
+
+    for (var bounce = 0u; bounce <= MAX_INTERNAL_REFLECTIONS; bounce++) {
+        var t = 0.0;
+        for (var i = 0u; i < settings.max_steps && t < settings.max_distance; i++) {
+            let d = -map(p + dir * t).dist;
+            if d < settings.eps {
+                break;
+            }
+            t += d;
+        }
+        p += dir * t;
+        length += t;
+
+        let n = normal(p);
+        let refracted = refract(dir, -n, ior);
+        if dot(refracted, refracted) > 0.0 {
+            return InteriorOutput(p, refracted, n, length);
+        }
+        dir = reflect(dir, -n);
+        p -= n * settings.shadow_eps;
+    }
+
+    return InteriorOutput(p, dir, normal(p), length);
+}
+#endif
+
+// Same inputs bevy_pbr builds for a StandardMaterial, so the BRDF of bevy_pbr::lighting can be used as is
+fn sd_lighting_input(p: vec3f, rd: vec3f, normal: vec3f, material: SdMaterial) -> LightingInput {
+    let V = -rd;
+    let NdotV = max(dot(normal, V), 0.0001);
+    let perceptual_roughness = material.roughness;
+
+    var input: LightingInput;
+    input.layers[LAYER_BASE].NdotV = NdotV;
+    input.layers[LAYER_BASE].N = normal;
+    input.layers[LAYER_BASE].R = reflect(-V, normal);
+    input.layers[LAYER_BASE].perceptual_roughness = perceptual_roughness;
+    input.layers[LAYER_BASE].roughness = perceptualRoughnessToRoughness(perceptual_roughness);
+    input.P = p;
+    input.V = V;
+    input.diffuse_color = material.color.rgb * (1.0 - material.metallic);
+#ifdef SD_TRANSMISSION
+    // Same as bevy_pbr, the transmitted light replaces part of the diffuse
+    input.diffuse_color *= 1.0 - material.specular_transmission;
+#endif
+    input.F0_ = specular_f0(material);
+    input.F_ab = F_AB(perceptual_roughness, NdotV);
+    return input;
+}
+
+fn apply_ambient(input: ptr<function, LightingInput>, material: SdMaterial, ao: f32) -> vec3f {
+    return ambient_light(
+        vec4f((*input).P, 1.0),
+        (*input).layers[LAYER_BASE].N,
+        (*input).V,
+        (*input).layers[LAYER_BASE].NdotV,
+        (*input).diffuse_color,
+        (*input).F0_,
+        (*input).layers[LAYER_BASE].perceptual_roughness,
+        vec3f(ao),
+    );
+}
+
+// `environment_map_light` of bevy_pbr for the map of the view, the SDFs have no light probes.
+// `radiance` is the light reflected by the surface, from the specular map or traced
+fn apply_image_based_light(input: ptr<function, LightingInput>, radiance: vec3f, ao: f32) -> vec3f {
+    let NdotV = (*input).layers[LAYER_BASE].NdotV;
+    let roughness = (*input).layers[LAYER_BASE].roughness;
+    let F0 = (*input).F0_;
+    let F_ab = (*input).F_ab;
+    let irradiance = environment_irradiance((*input).layers[LAYER_BASE].N);
+
+    // Multiscattering approximation: https://www.jcgt.org/published/0008/01/03/paper.pdf
+    let FssEss = environment_specular(input);
+    let Ems = 1.0 - (F_ab.x + F_ab.y);
+    let Favg = F0 + (1.0 - F0) / 21.0;
+    let Fms = FssEss * Favg / (1.0 - Ems * Favg);
+    let FmsEms = Fms * Ems;
+    let Edss = 1.0 - (FssEss + FmsEms);
+    let kD = (*input).diffuse_color * Edss;
+
+    // Same occlusion terms bevy_pbr derives from SSAO
+    let ao_specular = saturate(pow(NdotV + ao, exp2(-16.0 * roughness - 1.0)) - 1.0 + ao);
+    return (FmsEms + kD) * irradiance * ao + FssEss * radiance * ao_specular;
+}
+
+// Share of the reflected radiance that leaves the surface
+fn environment_specular(input: ptr<function, LightingInput>) -> vec3f {
+    let F0 = (*input).F0_;
+    let F_ab = (*input).F_ab;
+    let specular_occlusion = saturate(dot(F0, vec3(50.0 * 0.33)));
+    return (F0 * F_ab.x + F_ab.y) * specular_occlusion;
+}
+
+fn environment_irradiance(N: vec3f) -> vec3f {
+#ifdef SD_ENVIRONMENT_MAP
+    return sample_environment_map(diffuse_environment_map, N, 0.0);
+#else
+    return vec3f(0.0);
+#endif
+}
+
+// Split-sum approximation, rougher surfaces read blurrier mips of the specular map
+fn environment_radiance(dir: vec3f, perceptual_roughness: f32) -> vec3f {
+#ifdef SD_ENVIRONMENT_MAP
+    let level = perceptual_roughness * f32(textureNumLevels(specular_environment_map) - 1u);
+    return sample_environment_map(specular_environment_map, dir, level);
+#else
+    return vec3f(0.0);
+#endif
+}
+
+#ifdef SD_ENVIRONMENT_MAP
+fn sample_environment_map(cubemap: texture_cube<f32>, dir: vec3f, level: f32) -> vec3f {
+    var sample_dir = (settings.environment_map_transform * vec4f(dir, 1.0)).xyz;
+    // Cube maps are left-handed so we negate the z coordinate
+    sample_dir.z = -sample_dir.z;
+    return textureSampleLevel(cubemap, environment_map_sampler, sample_dir, level).rgb * settings.environment_map_intensity;
+}
+#endif
+
+// Same as bevy_pbr, the exposure weight picks between raw and exposed emissive
+fn apply_emissive(material: SdMaterial) -> vec3f {
+    let emissive = material.emissive * material.color.a;
+    return emissive * mix(1.0, view.exposure, material.emissive_exposure_weight);
 }
 
-fn apply_ambient(material: SdMaterial) -> vec3f {
-    let ambient = lights.ambient_color.rgb / 1000.0;
-    return material.color.rgb * ambient;
+// Specular color at normal incidence, same as `calculate_F0` of bevy_pbr with the tinted reflectance
+fn specular_f0(material: SdMaterial) -> vec3f {
+    let reflectance = material.specular_tint * material.reflectance;
+    return 0.16 * reflectance * reflectance * (1.0 - material.metallic) + material.color.rgb * material.metallic;
+}
+
+// Premultiplied output for the blit, which blends with PREMULTIPLIED_ALPHA_BLENDING
+fn apply_alpha_mode(color: vec3f, material: SdMaterial) -> vec4f {
+    let alpha = material.color.a;
+    switch sd_material_alpha_mode(material) {
+        case SD_MATERIAL_ALPHA_MODE_BLEND {
+            return vec4f(color * alpha, alpha);
+        }
+        case SD_MATERIAL_ALPHA_MODE_ADD {
+            return vec4f(color * alpha, 0.0);
+        }
+        case SD_MATERIAL_ALPHA_MODE_OPAQUE, SD_MATERIAL_ALPHA_MODE_MASK {
+            return vec4f(color, 1.0);
+        }
+        // Premultiplied
+        default {
+            return vec4f(color, alpha);
+        }
+    }
+}
+
+// Masked pixels are written as misses so whatever is behind shows through
+fn is_alpha_masked(material: SdMaterial) -> bool {
+    return sd_material_alpha_mode(material) == SD_MATERIAL_ALPHA_MODE_MASK && material.color.a < material.alpha_cutoff;
 }
 
 fn should_skip_light(light: ClusterableObject) -> bool {
@@ -279,61 +790,109 @@ fn should_skip_light(light: ClusterableObject) -> bool {
 
 fn apply_light_contribution(
     i: u32,
-    ro: vec3f,
-    rd: vec3f,
-    normal: vec3f,
+    input: ptr<function, LightingInput>,
     material: SdMaterial,
 ) -> vec3f {
     let light = clusterable_objects.data[i];
     let light_pos = light.position_radius.xyz;
-    let light_color = light.color_inverse_square_range.rgb / 3000.0;
+    let light_color = light.color_inverse_square_range.rgb;
     let light_range_inv_sq = light.color_inverse_square_range.w;
 
+    let ro = (*input).P;
+    let rd = -(*input).V;
+    let normal = (*input).layers[LAYER_BASE].N;
+
     let to_light = light_pos - ro ;
     let dist_sq = dot(to_light, to_light);
     let light_dir = normalize(to_light);
     let dist = sqrt(dist_sq);
 
-    // === AO and Shadowing ===
+    // === Shadowing ===
+    var visibility = 1.0;
+    if (light.flags & POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u {
+        visibility = sd_shadow(
+            ro + normal * settings.shadow_eps,
+            light_dir,
+            min(dist, settings.shadow_max_distance),
+        ) * fetch_mesh_shadow(i, light, ro, normal);
+    }
 
-    let ao = calc_ao(ro, normal);
+    let attenuation = getDistanceAttenuation(dist_sq, light_range_inv_sq) * spot_attenuation(light, light_dir);
 
-    // let visibility = shadow(
-    //     ro + normal * settings.shadow_eps,
-    //     light_dir,
-    //     settings.shadow_eps,
-    //     min(dist, settings.shadow_max_distance),
-    //     settings.shadow_max_steps
-    // );
+    // === Subsurface Scattering Approximation ===
+    // Divided by PI like the Burley diffuse it replaces
+    let sss_contrib = compute_sss(ro, rd, normal, light_dir, light_color, material) * attenuation / PI;
 
-    // let visibility = softshadow(
-    //     ro + normal * settings.shadow_eps,
-    //     light_dir,
-    //     settings.shadow_eps,
-    //     min(dist, settings.shadow_max_distance),
-    //     settings.shadow_max_steps,
-    //     settings.shadow_softness,
-    // );
+    // === Diffuse and Specular ===
+    var standard_contrib: vec3f;
+    if light.spot_light_tan_angle > 0.0 {
+        standard_contrib = spot_light(i, input, true);
+    } else {
+        standard_contrib = point_light(i, input, true, false);
+    }
 
+    // === Combine ===
+    return mix(standard_contrib, sss_contrib, material.sss_strength) * visibility;
+}
 
+// Shadow cast by meshes, sampled from the shadow map Bevy rendered for the light
+fn fetch_mesh_shadow(i: u32, light: ClusterableObject, p: vec3f, normal: vec3f) -> f32 {
+    if light.spot_light_tan_angle > 0.0 {
+        return fetch_spot_shadow(i, vec4f(p, 1.0), normal, light.shadow_map_near_z);
+    }
+    return fetch_point_shadow(i, vec4f(p, 1.0), normal);
+}
 
-    let attenuation = getDistanceAttenuation(dist_sq, light_range_inv_sq);
-    let diff = max(dot(normal, light_dir), 0.0);
-    let mat_color = material.color.rgb;
+// Cone attenuation of a spot light, 1 for point lights.
+// Only for the SSS and volume terms, the BRDF gets it from the `spot_light` of bevy_pbr.
+// Spot lights are the only clusterable objects with a cone angle, see `prepare_lights` of bevy_pbr.
+fn spot_attenuation(light: ClusterableObject, light_dir: vec3f) -> f32 {
+    if light.spot_light_tan_angle <= 0.0 {
+        return 1.0;
+    }
+
+    // The direction is stored as x/z with the sign of y in the flags
+    var spot_dir = vec3f(light.light_custom_data.x, 0.0, light.light_custom_data.y);
+    spot_dir.y = sqrt(max(0.0, 1.0 - spot_dir.x * spot_dir.x - spot_dir.z * spot_dir.z));
+    if (light.flags & POINT_LIGHT_FLAGS_SPOT_LIGHT_Y_NEGATIVE) != 0u {
+        spot_dir.y = -spot_dir.y;
+    }
+
+    // spot_scale and spot_offset are precomputed from the inner and outer angles
+    let cd = dot(-spot_dir, light_dir);
+    let attenuation = saturate(cd * light.light_custom_data.z + light.light_custom_data.w);
+    return attenuation * attenuation;
+}
+
+fn apply_directional_light_contribution(
+    i: u32,
+    input: ptr<function, LightingInput>,
+    material: SdMaterial,
+) -> vec3f {
+    let light = lights.directional_lights[i];
+    let light_dir = light.direction_to_light;
+
+    let ro = (*input).P;
+    let rd = -(*input).V;
+    let normal = (*input).layers[LAYER_BASE].N;
+
+    // === Shadowing ===
+    // The light is infinitely far so the shadow ray only stops at shadow_max_distance
+    var visibility = 1.0;
+    if (light.flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u {
+        let view_z = (view.view_from_world * vec4f(ro, 1.0)).z;
+        visibility = sd_shadow(ro + normal * settings.shadow_eps, light_dir, settings.shadow_max_distance)
+            * fetch_directional_shadow(i, vec4f(ro, 1.0), normal, view_z);
+    }
 
     // === Subsurface Scattering Approximation ===
-    let sss_contrib = compute_sss(ro, rd, normal, light_dir, light_color, material);
+    let sss_contrib = compute_sss(ro, rd, normal, light_dir, light.color.rgb, material) / PI;
 
     // === Diffuse and Specular ===
-    let reflect_dir = reflect(-light_dir, normal);
-    let spec = pow(max(dot(-rd, reflect_dir), 0.0), 64.0);
-    let specular_strength = material.roughness;
-    let lighting = (diff + specular_strength * spec) * attenuation /*  visibility */ * ao;
+    let standard_contrib = directional_light(i, input, true);
 
     // === Combine ===
-    let standard_contrib = mat_color * light_color * lighting;
-    let blended = mix(standard_contrib, sss_contrib * attenuation  /* visibility */ * ao, material.sss_strength);
-    return blended;
+    return mix(standard_contrib, sss_contrib, material.sss_strength) * visibility;
 }
 
 fn compute_sss(
@@ -386,8 +945,140 @@ fn compute_sss(
 }
 
 
-@compute @workgroup_size(8, 8, 1)
-fn compute_raymarch(@builtin(global_invocation_id) id: vec3u) {
+#ifdef SD_VOLUMES
+struct VolumeSample {
+    // Distance to the nearest volume, negative inside
+    dist: f32,
+    extinction: f32,
+    scattering: vec3f,
+    anisotropy: f32,
+}
+
+// Media of every SdVolume at `p`, the density grows with the negated distance up to `falloff` deep.
+// A volume is evaluated like an instance of its subtree.
+fn sample_volumes(p: vec3f) -> VolumeSample {
+    var media = VolumeSample(EMPTY_DIST, 0.0, vec3f(0.0), 0.0);
+    for (var i = 0u; i < arrayLength(&sd_volumes); i++) {
+        let volume = sd_volumes[i];
+        let d = instance_to_dist(unpack_sd_object(sd_object[volume.object]), p).dist;
+        let density = volume.density * saturate(-d / max(volume.falloff, 1e-4));
+
+        media.dist = min(media.dist, d);
+        media.extinction += density;
+        media.scattering += density * volume.albedo;
+        media.anisotropy += density * volume.anisotropy;
+    }
+    if media.extinction > 0.0 {
+        media.anisotropy /= media.extinction;
+    }
+    return media;
+}
+
+struct VolumeOutput {
+    // In-scattered light, exposed like the surfaces
+    radiance: vec3f,
+    transmittance: f32,
+    // Where the ray enters the media
+    entry: f32,
+}
+
+// Fixed steps through the media up to `max_t`, the empty space between volumes is sphere traced
+fn march_volumes(ro: vec3f, rd: vec3f, max_t: f32) -> VolumeOutput {
+    var result = VolumeOutput(vec3f(0.0), 1.0, max_t);
+    var t = 0.0;
+
+    for (var i = 0u; i < settings.volume_max_steps && t < max_t; i++) {
+        let p = ro + rd * t;
+        let media = sample_volumes(p);
+        if media.dist > 0.0 {
+            t += max(media.dist, settings.volume_step_size);
+            continue;
+        }
+
+        let step = min(settings.volume_step_size, max_t - t);
+        if media.extinction > 0.0 {
+            result.entry = min(result.entry, t);
+
+            // Beer-Lambert over the step, the in-scattering is integrated along it like in
+            // "Physically Based and Unified Volumetric Rendering in Frostbite" (Hillaire 2015)
+            let step_transmittance = exp(-media.extinction * step);
+            let in_scattering = media.scattering * volume_in_scattering(p, rd, media.anisotropy);
+            result.radiance += result.transmittance * (in_scattering - in_scattering * step_transmittance) / media.extinction;
+            result.transmittance *= step_transmittance;
+
+            if result.transmittance < 0.01 {
+                result.transmittance = 0.0;
+                break;
+            }
+        }
+        t += step;
+    }
+
+    // Same as bevy_pbr, the lights go through the view exposure
+    result.radiance *= view.exposure;
+    return result;
+}
+
+// Single scattering of the scene lights at `p` towards the camera, the ambient light is isotropic
+fn volume_in_scattering(p: vec3f, rd: vec3f, anisotropy: f32) -> vec3f {
+    var light = lights.ambient_color.rgb + environment_irradiance(-rd);
+
+    for (var i = 0u; i < lights.n_directional_lights; i++) {
+        let directional = lights.directional_lights[i];
+        let light_dir = directional.direction_to_light;
+
+        var visibility = volume_transmittance(p, light_dir);
+        if (directional.flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u {
+            let view_z = (view.view_from_world * vec4f(p, 1.0)).z;
+            visibility *= fetch_directional_shadow(i, vec4f(p, 1.0), light_dir, view_z);
+        }
+
+        light += directional.color.rgb * henyey_greenstein(dot(rd, light_dir), anisotropy) * visibility;
+    }
+
+    for (var i = 0u; i < arrayLength(&clusterable_objects.data); i++) {
+        let clusterable = clusterable_objects.data[i];
+        if should_skip_light(clusterable) {
+            continue;
+        }
+        let to_light = clusterable.position_radius.xyz - p;
+        let dist_sq = dot(to_light, to_light);
+        let light_dir = to_light * inverseSqrt(dist_sq);
+
+        var visibility = volume_transmittance(p, light_dir);
+        if (clusterable.flags & POINT_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u {
+            visibility *= fetch_mesh_shadow(i, clusterable, p, light_dir);
+        }
+
+        let attenuation = getDistanceAttenuation(dist_sq, clusterable.color_inverse_square_range.w) * spot_attenuation(clusterable, light_dir);
+        light += clusterable.color_inverse_square_range.rgb * attenuation * henyey_greenstein(dot(rd, light_dir), anisotropy) * visibility;
+    }
+
+    return light;
+}
+
+// Shadow the media casts on itself towards a light, the steps double in length
+// so the shadow reaches further than volume_step_size * volume_light_steps
+fn volume_transmittance(p: vec3f, light_dir: vec3f) -> f32 {
+    var optical_depth = 0.0;
+    var step = settings.volume_step_size;
+    var t = 0.0;
+    for (var i = 0u; i < settings.volume_light_steps; i++) {
+        t += step;
+        optical_depth += sample_volumes(p + light_dir * t).extinction * step;
+        step *= 2.0;
+    }
+    return exp(-optical_depth);
+}
+
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * denom * sqrt(denom));
+}
+#endif
+
+// The entry points are in ray_march_pass.wgsl so a compiled map can reuse them
+fn raymarch(id: vec3u) {
 
     let buffer_size = view.viewport.zw * settings.depth_scale;
 
@@ -401,20 +1092,38 @@ fn compute_raymarch(@builtin(global_invocation_id) id: vec3u) {
 
     let m = march(ro, rd);
 
-    let material = apply_lighting(m.pos, rd, m.normal, m.material);
-    
+    var color = apply_lighting(m.pos, rd, m.normal, m.material);
+#ifdef SD_TRANSMISSION
+    if m.depth <= settings.max_distance && m.material.specular_transmission > 0.0 {
+        color += trace_transmission(m, rd, mesh_distance(id.xy, uv, ro));
+    }
+#endif
+    var material = apply_alpha_mode(color, m.material);
+
     let p_ndc = position_world_to_ndc(m.pos, view.clip_from_world);
     let ray_depth = p_ndc.z;
 
-    let depth = select(ray_depth, -0.001, m.depth > settings.max_distance);
+    var depth = select(ray_depth, -0.001, m.depth > settings.max_distance || is_alpha_masked(m.material));
+
+#ifdef SD_VOLUMES
+    // Media in front of the SDFs and the meshes goes over them like a translucent surface
+    let surface = select(material, vec4f(0.0), depth < 0.0);
+    let max_t = min(select(m.depth, settings.max_distance, depth < 0.0), mesh_distance(id.xy, uv, ro));
+    let media = march_volumes(ro, rd, max_t);
+    if media.transmittance < 1.0 {
+        material = vec4f(media.radiance + media.transmittance * surface.rgb, 1.0 - media.transmittance * (1.0 - surface.a));
+        if depth < 0.0 {
+            depth = position_world_to_ndc(ro + rd * media.entry, view.clip_from_world).z;
+        }
+    }
+#endif
 
     textureStore(depth_prepass, id.xy, vec4f(depth));
     textureStore(normal_prepass, id.xy, vec4f(vec3f(m.normal * .5 + .5), 1.));
-    textureStore(material_prepass, id.xy, vec4f(vec3f(material), 1.));
+    textureStore(material_prepass, id.xy, material);
 }
 
-@compute @workgroup_size(8, 8, 1)
-fn compute_mask(@builtin(global_invocation_id) id: vec3u) {
+fn raymarch_mask(id: vec3u) {
 
     let scaled_id = vec2u(vec2f(id.xy) * settings.depth_scale);
     let depth_pass = textureLoad(depth_prepass, scaled_id);
@@ -422,3 +1131,24 @@ fn compute_mask(@builtin(global_invocation_id) id: vec3u) {
 
     textureStore(mask_prepass, id.xy, vec4f(depth_pass.x < world_depth) );
 }
+
+// Depth of the SDFs seen from a light, `view` is one of the shadow views of the light.
+// Negative when the ray hits nothing
+fn shadow_caster_depth(screen_uv: vec2f) -> f32 {
+    let uv = vec2f(screen_uv.x * 2.0 - 1.0, 1.0 - screen_uv.y * 2.0);
+
+    let temp = view.world_from_clip * vec4f(uv, 1.0, 1.0);
+    let ro = temp.xyz / temp.w;
+    let rd = normalize(ro * view.world_from_clip[2].w - view.world_from_clip[2].xyz);
+
+    var t = 0.0;
+    for (var i = 0u; i < settings.max_steps && t < settings.max_distance; i++) {
+        let d = map(ro + rd * t).dist;
+        if d < settings.eps {
+            return position_world_to_ndc(ro + rd * t, view.clip_from_world).z;
+        }
+        t += d;
+    }
+
+    return -1.0;
+}
@@ -0,0 +1,21 @@
+#import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
+#import bevy_sdf::ray_march::{raymarch, raymarch_mask, shadow_caster_depth}
+
+@compute @workgroup_size(8, 8, 1)
+fn compute_raymarch(@builtin(global_invocation_id) id: vec3u) {
+    raymarch(id);
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn compute_mask(@builtin(global_invocation_id) id: vec3u) {
+    raymarch_mask(id);
+}
+
+@fragment
+fn shadow_caster(in: FullscreenVertexOutput) -> @builtin(frag_depth) f32 {
+    let depth = shadow_caster_depth(in.uv);
+    if depth < 0.0 {
+        discard;
+    }
+    return depth;
+}
@@ -1,19 +1,6 @@
 #define_import_path bevy_sdf::selectors
 
-#import bevy_sdf::bindings::{
-    sd_mod,
-    sd_field_data,
-}
-
-#import bevy_sdf::utils::{
-    // Signed distance primitives
-    sdBunny, sdBox, sdBoxFrame, sdCappedCone, sdCappedTorus, sdCappedVerticalCone,
-    sdCapsule, sdCone, sdConeBound, sdEllipsoid, sdGyroid, sdHexPrism, sdInfiniteCone,
-    sdInfiniteCylinder, sdJuliaQuaternion, sdLink, sdMandelbulb, sdMengerSponge,
-    sdOctahedron, sdOctahedronBound, sdPlane, sdCylinder, sdPyramid, sdRoundBox, sdRoundCone,
-    sdRoundVerticalCone, sdRoundedCylinder, sdSolidAngle, sdSphere, sdTorus,
-    sdTriPrism, sdVerticalCapsule, sdVerticalCylinder, udTriangle,
-}
+#import bevy_sdf::bindings::sd_mod
 
 #import bevy_sdf::utils::{
     // Operations
@@ -25,6 +12,10 @@
     opUnion, op90RotateX, op90RotateY, op90RotateZ,
 }
 
+#import bevy_sdf::shapes::select_shape_dist
+#import bevy_sdf::mods::apply_custom_mod
+#import bevy_sdf::blends::select_custom_blend
+
 #import bevy_sdf::types::{
     SdBlend, SdMod, SdModStack, SdShape, SdTransform,
 }
@@ -36,280 +27,9 @@ fn select_shape(p: vec3f, shape: SdShape, transform: SdTransform, modifiers: SdM
         pos = apply_mod(pos, sd_mod[modifiers.start_index + i]);
     }
 
-    switch shape.type_id {
-        case 0u, default {
-            return sdSphere(pos, sd_field_data[shape.data_index]);
-        }
-        case 1u {
-            return sdEllipsoid(pos, vec3f(
-                sd_field_data[shape.data_index + 0],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            ));
-        }
-        case 2u {
-            return sdBox(pos, vec3f(
-                sd_field_data[shape.data_index + 0],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            ));
-        }
-        case 3u {
-            return sdRoundBox(pos, vec3f(
-                sd_field_data[shape.data_index + 0],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            ), sd_field_data[shape.data_index + 3]);
-        }
-        case 4u {
-            return sdBoxFrame(pos, vec3f(
-                sd_field_data[shape.data_index + 0],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            ), sd_field_data[shape.data_index + 3]);
-        }
-        case 5u {
-            return sdGyroid(pos, sd_field_data[shape.data_index]);
-        }
-        case 6u {
-            return sdTorus(pos,
-                sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1]
-            );
-        }
-        case 7u {
-            return sdCappedTorus(pos,
-                sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1],
-                vec2f(sd_field_data[shape.data_index + 2], sd_field_data[shape.data_index + 3])
-            );
-        }
-        case 8u {
-            return sdLink(pos,
-                sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            );
-        }
-        case 9u {
-            return sdVerticalCapsule(pos,
-                sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1]
-            );
-        }
-        case 10u {
-            return sdCapsule(pos,
-                vec3f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                ),
-                vec3f(
-                    sd_field_data[shape.data_index + 3],
-                    sd_field_data[shape.data_index + 4],
-                    sd_field_data[shape.data_index + 5]
-                ),
-                sd_field_data[shape.data_index + 6]
-            );
-        }
-        case 11u {
-            return sdCylinder(pos,
-                vec3f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                ),
-                vec3f(
-                    sd_field_data[shape.data_index + 3],
-                    sd_field_data[shape.data_index + 4],
-                    sd_field_data[shape.data_index + 5]
-                ),
-                sd_field_data[shape.data_index + 6]
-            );
-        }
-        case 12u {
-            return sdVerticalCylinder(pos,
-                sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1]
-            );
-        }
-        case 13u {
-            return sdRoundedCylinder(pos,
-                sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            );
-        }
-        case 14u {
-            return sdInfiniteCylinder(pos,
-                vec3f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                )
-            );
-        }
-        case 15u {
-            return sdCone(pos,
-                sd_field_data[shape.data_index],
-                vec2f(
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                )
-            );
-        }
-        case 16u {
-            return sdConeBound(pos,
-                sd_field_data[shape.data_index],
-                vec2f(
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                )
-            );
-        }
-        case 17u {
-            return sdInfiniteCone(pos,
-                vec2f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1]
-                )
-            );
-        }
-        case 18u {
-            return sdCappedVerticalCone(pos,
-                sd_field_data[shape.data_index + 0],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            );
-        }
-        case 19u {
-            return sdCappedCone(pos,
-                vec3f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                ),
-                vec3f(
-                    sd_field_data[shape.data_index + 3],
-                    sd_field_data[shape.data_index + 4],
-                    sd_field_data[shape.data_index + 5]
-                ),
-                sd_field_data[shape.data_index + 6],
-                sd_field_data[shape.data_index + 7]
-            );
-        }
-        case 20u {
-            return sdRoundVerticalCone(pos,
-                sd_field_data[shape.data_index + 0],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2]
-            );
-        }
-        case 21u {
-            return sdRoundCone(pos,
-                vec3f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                ),
-                vec3f(
-                    sd_field_data[shape.data_index + 3],
-                    sd_field_data[shape.data_index + 4],
-                    sd_field_data[shape.data_index + 5]
-                ),
-                sd_field_data[shape.data_index + 6],
-                sd_field_data[shape.data_index + 7]
-            );
-        }
-        case 22u {
-            return sdSolidAngle(pos,
-                vec2f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1]
-                ),
-                sd_field_data[shape.data_index + 2]
-            );
-        }
-        case 23u {
-            return sdPlane(pos,
-                vec3f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                ),
-                sd_field_data[shape.data_index + 3]
-            );
-        }
-        case 24u {
-            return sdOctahedron(pos, sd_field_data[shape.data_index]);
-        }
-        case 25u {
-            return sdOctahedronBound(pos, sd_field_data[shape.data_index]);
-        }
-        case 26u {
-            return sdPyramid(pos, sd_field_data[shape.data_index]);
-        }
-        case 27u {
-            return sdHexPrism(pos,
-                vec2f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1]
-                )
-            );
-        }
-        case 28u {
-            return sdTriPrism(pos,
-                vec2f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1]
-                )
-            );
-        }
-        case 29u {
-            return udTriangle(pos,
-                vec3f(
-                    sd_field_data[shape.data_index + 0],
-                    sd_field_data[shape.data_index + 1],
-                    sd_field_data[shape.data_index + 2]
-                ),
-                vec3f(
-                    sd_field_data[shape.data_index + 3],
-                    sd_field_data[shape.data_index + 4],
-                    sd_field_data[shape.data_index + 5]
-                ),
-                vec3f(
-                    sd_field_data[shape.data_index + 6],
-                    sd_field_data[shape.data_index + 7],
-                    sd_field_data[shape.data_index + 8]
-                )
-            );
-        }
-        case 30u {
-            return sdBunny(pos / sd_field_data[shape.data_index]) * sd_field_data[shape.data_index];
-        }
-        case 31u {
-            return sdMandelbulb(
-                pos / sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1],
-                sd_field_data[shape.data_index + 2],
-                sd_field_data[shape.data_index + 3]
-            ) * sd_field_data[shape.data_index];
-        }
-        case 32u {
-            return sdJuliaQuaternion(
-                pos / sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1]
-            ) * sd_field_data[shape.data_index];
-        }
-        case 33u {
-            return sdMengerSponge(
-                pos / sd_field_data[shape.data_index],
-                sd_field_data[shape.data_index + 1]
-            ) * sd_field_data[shape.data_index];
-        }
-    }
+    return select_shape_dist(pos, shape.type_id, shape.data_index);
 }
- 
+
 fn apply_mod(p: vec3f, modifier: SdMod) -> vec3f {
     switch modifier.type_id {
         case 0u {
@@ -361,7 +81,7 @@ fn apply_mod(p: vec3f, modifier: SdMod) -> vec3f {
             return opElongate(p, modifier.data.xyz);
         }
         case default {
-            return p;
+            return apply_custom_mod(p, modifier);
         }
     }
 }
@@ -376,7 +96,7 @@ fn apply_transform(p: vec3f, transform: SdTransform) -> vec3f {
 
 fn select_blend(op: SdBlend, d1: f32, d2: f32) -> vec2f {
     switch op.type_id {
-        case 0u, default {
+        case 0u {
             return opUnion(d1, d2);
         }
         case 1u { // Subtract
@@ -390,37 +110,40 @@ fn select_blend(op: SdBlend, d1: f32, d2: f32) -> vec2f {
             return opIntersect(d1, d2);
         }
         case 3u {
-            return opChamferUnion(d1, d2, op.data.x);
+            return opChamferUnion(d1, d2, op.data);
         }
         case 4u { // ChamferSubtract
             if op.rev {
-                return opChamferSubtract(d1, d2, op.data.x);
+                return opChamferSubtract(d1, d2, op.data);
             } else {
-                return opChamferSubtract(d2, d1, op.data.x);
+                return opChamferSubtract(d2, d1, op.data);
             };
         }
         case 5u {
-            return opChamferIntersect(d1, d2, op.data.x);
+            return opChamferIntersect(d1, d2, op.data);
         }
         case 6u {
-            return opSmoothUnion(d1, d2, op.data.x);
+            return opSmoothUnion(d1, d2, op.data);
         }
         case 7u { // SmoothSubtract
             if op.rev {
-                return opSmoothSubtract(d1, d2, op.data.x);
+                return opSmoothSubtract(d1, d2, op.data);
             } else {
-                return opSmoothSubtract(d2, d1, op.data.x);
+                return opSmoothSubtract(d2, d1, op.data);
             };
         }
         case 8u {
-            return opSmoothIntersect(d1, d2, op.data.x);
+            return opSmoothIntersect(d1, d2, op.data);
         }
         case 9u { // Displace
             if op.rev {
-                return opDisplace(d1, d2, op.data.x);
+                return opDisplace(d1, d2, op.data);
             } else {
-                return opDisplace(d2, d1, op.data.x);
+                return opDisplace(d2, d1, op.data);
             };
         }
+        case default {
+            return select_custom_blend(op, d1, d2);
+        }
     }
 }
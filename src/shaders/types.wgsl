@@ -2,14 +2,15 @@
 
 struct SdObject {
     shape: SdShape,
-    material: SdMaterial,
+    // Index in sd_materials
+    material: u32,
     modifiers: SdModStack,
     transform: SdTransform,
 }
 
 struct SdObjectPacked {
     shape: SdShapePacked,
-    material: SdMaterialPacked,
+    material: u32,
     modifiers: SdModStackPacked,
     transform: SdTransform,
 }
@@ -17,12 +18,29 @@ struct SdObjectPacked {
 fn unpack_sd_object(packed: SdObjectPacked) -> SdObject {
     return SdObject(
         unpack_sd_shape(packed.shape),
-        unpack_sd_material(packed.material),
+        packed.material,
         unpack_sd_mod_stack(packed.modifiers),
         packed.transform
     );
 }
 
+// Shape type id of an SdInstance, data_index and len are the range of its prefab in sd_prefab_ops
+const SD_INSTANCE_TYPE_ID: u32 = 0xFFu;
+// Shape type id of leaves that evaluate to nothing
+const SD_EMPTY_TYPE_ID: u32 = 0xFDu;
+
+// Shape type id of an SdVolume, its program is in sd_prefab_ops like a prefab
+const SD_VOLUME_TYPE_ID: u32 = 0xFEu;
+
+// Participating media, `object` is the index of the volume in sd_object
+struct SdVolume {
+    object: u32,
+    density: f32,
+    falloff: f32,
+    anisotropy: f32,
+    albedo: vec3f,
+}
+
 struct SdShape {
     type_id: u32,
     data_index: u32,
@@ -49,40 +67,80 @@ struct SdMaterial {
     metallic: f32,
     sss_strength: f32,
     sss_radius: vec3f,
-}
-
+    emissive: vec3f,
+    emissive_exposure_weight: f32,
+    reflectance: f32,
+    specular_tint: vec3f,
+    specular_transmission: f32,
+    ior: f32,
+    absorption: vec3f,
+    flags: u32,
+    alpha_cutoff: f32,
+}
+
+const SD_MATERIAL_FLAGS_UNLIT: u32 = 1u;
+const SD_MATERIAL_FLAGS_ALPHA_MODE_SHIFT: u32 = 1u;
+const SD_MATERIAL_ALPHA_MODE_OPAQUE: u32 = 0u;
+const SD_MATERIAL_ALPHA_MODE_MASK: u32 = 1u;
+const SD_MATERIAL_ALPHA_MODE_BLEND: u32 = 2u;
+const SD_MATERIAL_ALPHA_MODE_PREMULTIPLIED: u32 = 3u;
+const SD_MATERIAL_ALPHA_MODE_ADD: u32 = 4u;
+
+fn sd_material_alpha_mode(material: SdMaterial) -> u32 {
+    return (material.flags >> SD_MATERIAL_FLAGS_ALPHA_MODE_SHIFT) & 0x7u;
+}
+
+// Every channel is an f16 so HDR colors and small values survive
 struct SdMaterialPacked {
-    color: u32,
-    rough_fres_metal: u32,
-    sss_strength_radius: u32,
+    color_rg: u32,
+    color_ba: u32,
+    roughness_fresnel: u32,
+    metallic_sss_strength: u32,
+    sss_radius_rg: u32,
+    sss_radius_b: u32,
+    emissive_rg: u32,
+    emissive_b_exposure_weight: u32,
+    specular_tint_rg: u32,
+    specular_tint_b_reflectance: u32,
+    transmission_ior: u32,
+    // Beer-Lambert absorption coefficients per unit of distance inside the surface
+    absorption_rg: u32,
+    absorption_b: u32,
+    // Flags in the low half and the f16 alpha cutoff in the high half
+    flags_alpha_cutoff: u32,
 }
 
 fn unpack_sd_material(packed: SdMaterialPacked) -> SdMaterial {
-    let rgba = unpack4x8unorm(packed.color);
-    let r_f_m = unpack4x8unorm(packed.rough_fres_metal);
-    let sss_st_rgb = unpack4x8unorm(packed.sss_strength_radius);
-    return SdMaterial(rgba, r_f_m.r, r_f_m.g, r_f_m.b, sss_st_rgb.r, sss_st_rgb.gba);
-}
-
-fn pack_sd_material(material: SdMaterial) -> SdMaterialPacked {
-    let color = pack4x8unorm(material.color);
-    let rough_fres_metal = pack4x8unorm(vec4f(
-        material.roughness,
-        material.fresnel,
-        material.metallic,
-        0.
-    ));
-    let sss_strength_radius = pack4x8unorm(vec4f(
-        material.sss_strength,
-        material.sss_radius.x,
-        material.sss_radius.y,
-        material.sss_radius.z,
-    ));
-    return SdMaterialPacked(color, rough_fres_metal, sss_strength_radius);
+    let color = vec4f(unpack2x16float(packed.color_rg), unpack2x16float(packed.color_ba));
+    let r_f = unpack2x16float(packed.roughness_fresnel);
+    let m_sss = unpack2x16float(packed.metallic_sss_strength);
+    let sss_radius = vec3f(unpack2x16float(packed.sss_radius_rg), unpack2x16float(packed.sss_radius_b).x);
+    let emissive_b_w = unpack2x16float(packed.emissive_b_exposure_weight);
+    let emissive = vec3f(unpack2x16float(packed.emissive_rg), emissive_b_w.x);
+    let specular_b_r = unpack2x16float(packed.specular_tint_b_reflectance);
+    let specular_tint = vec3f(unpack2x16float(packed.specular_tint_rg), specular_b_r.x);
+    let t_ior = unpack2x16float(packed.transmission_ior);
+    let absorption = vec3f(unpack2x16float(packed.absorption_rg), unpack2x16float(packed.absorption_b).x);
+    let flags = packed.flags_alpha_cutoff & 0xFFFFu;
+    let alpha_cutoff = unpack2x16float(packed.flags_alpha_cutoff).y;
+    return SdMaterial(color, r_f.x, r_f.y, m_sss.x, m_sss.y, sss_radius, emissive, emissive_b_w.y, specular_b_r.y, specular_tint, t_ior.x, t_ior.y, absorption, flags, alpha_cutoff);
+}
+
+// Two entries of sd_materials and how much of `b` is mixed into `a`,
+// ops only blend these and the materials are read once at shading
+struct SdMaterialMix {
+    a: u32,
+    b: u32,
+    t: f32,
+}
+
+fn single_material(index: u32) -> SdMaterialMix {
+    return SdMaterialMix(index, index, 0.0);
 }
 
 struct SdMod {
     type_id: u32,
+    data_index: u32,
     data: vec4f,
 }
 
@@ -133,19 +191,22 @@ fn unpack_sd_operator(packed: SdOperatorPacked) -> SdOperator {
 struct SdBlend {
     type_id: u32,
     rev: bool,
-    data: vec2f,
+    data: f32,
+    // Index of the params of a custom blend in sd_field_data
+    data_index: u32,
 }
 
 struct SdBlendPacked {
-    type_id_data: u32
+    type_id_rev: u32,
+    data: f32,
+    data_index: u32,
 }
 
 fn unpack_sd_blend(packed: SdBlendPacked) -> SdBlend {
-    let id = packed.type_id_data & 0x000000FFu;               // bits 0..7
-    let rev = (packed.type_id_data >> 8) & 0x000000FFu;       // bits 8..15
+    let id = packed.type_id_rev & 0x000000FFu;               // bits 0..7
+    let rev = (packed.type_id_rev >> 8) & 0x000000FFu;       // bits 8..15
 
-    let data_approx = f32((packed.type_id_data >> 16) & 0x0000FFFFu) / 255.0;
-    return SdBlend(id, bool(rev), vec2f(data_approx, 0.0)); // second float unused here
+    return SdBlend(id, bool(rev), packed.data, packed.data_index);
 }
 
 struct MarchOutput {
@@ -157,23 +218,5 @@ struct MarchOutput {
 
 struct DistanceInfo {
     dist: f32,
-    material: SdMaterial,
-}
-
-struct DistanceInfoPacked {
-    dist: f32,
-    material: SdMaterialPacked,
-}
-
-fn unpack_distance_info(packed: DistanceInfoPacked) -> DistanceInfo {
-    return DistanceInfo(packed.dist, unpack_sd_material(packed.material));
-}
-
-fn pack_distance_info(info: DistanceInfo) -> DistanceInfoPacked {
-    return DistanceInfoPacked(
-        info.dist,
-        pack_sd_material(info.material)
-    );
+    material: SdMaterialMix,
 }
-
-
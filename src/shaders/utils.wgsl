@@ -430,6 +430,23 @@ fn sdMengerSponge(p: vec3f, iter: f32) -> f32 {
     return d;
 }
 
+// The fractals and the bunny are unit sized, these scale them for the SdShape dispatch
+fn sdScaledBunny(p: vec3f, s: f32) -> f32 {
+    return sdBunny(p / s) * s;
+}
+
+fn sdScaledMandelbulb(p: vec3f, scale: f32, iter: f32, expo: f32, b_offset: f32) -> f32 {
+    return sdMandelbulb(p / scale, iter, expo, b_offset) * scale;
+}
+
+fn sdScaledJuliaQuaternion(p: vec3f, scale: f32, iter: f32) -> f32 {
+    return sdJuliaQuaternion(p / scale, iter) * scale;
+}
+
+fn sdScaledMengerSponge(p: vec3f, scale: f32, iter: f32) -> f32 {
+    return sdMengerSponge(p / scale, iter) * scale;
+}
+
 // === Boolean operations with primitives ===
 
 // Union, Subtraction, Intersection - exact (outside), bound, bound